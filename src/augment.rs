@@ -1,126 +1,995 @@
+use std::collections::{HashMap, HashSet};
+
+use indicatif::{ParallelProgressIterator, ProgressBar};
+use rayon::prelude::*;
+use serde::Deserialize;
+
 use crate::{
     config::Config,
-    point::Point,
-    point_map::PointMap,
-    rss_record::{RssArr, RssRecord},
+    point::{Point, Position},
+    point_map::RssMap,
+    rss_record::{Provenance, RssArr, RssRecord, Uncertainty},
 };
 
+#[derive(Debug, Clone)]
+pub enum Region {
+    Rect { ll: Point, ur: Point },
+    Polygon(Vec<[f32; 2]>),
+}
+
+impl Region {
+    pub fn contains(&self, p: &Point) -> bool {
+        match self {
+            Region::Rect { ll, ur } => ll.x <= p.x && p.x < ur.x && ll.y <= p.y && p.y < ur.y,
+            Region::Polygon(vertices) => {
+                let (x, y) = (p.x as f32, p.y as f32);
+                let mut inside = false;
+                let mut j = vertices.len() - 1;
+                for i in 0..vertices.len() {
+                    let [xi, yi] = vertices[i];
+                    let [xj, yj] = vertices[j];
+                    if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+                inside
+            }
+        }
+    }
+
+    fn bounds(&self) -> (Point, Point) {
+        match self {
+            Region::Rect { ll, ur } => (*ll, *ur),
+            Region::Polygon(vertices) => {
+                let (x0, y0, x1, y1) = vertices.iter().fold(
+                    (
+                        f32::INFINITY,
+                        f32::INFINITY,
+                        f32::NEG_INFINITY,
+                        f32::NEG_INFINITY,
+                    ),
+                    |(x0, y0, x1, y1), &[x, y]| (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                );
+                (
+                    Point::new(x0.floor() as i64, y0.floor() as i64),
+                    Point::new(x1.ceil() as i64 + 1, y1.ceil() as i64 + 1),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct AugmentBox {
-    ll: Point,
-    ur: Point,
-    resolution: u32,
+    region: Region,
+    resolution: usize,
 }
 
 impl AugmentBox {
     pub fn new(ll: Point, ur: Point) -> Self {
         Self {
-            ll,
-            ur,
+            region: Region::Rect { ll, ur },
             resolution: 10,
         }
     }
 
-    pub fn new_with_size(ll: Point, w: u32, h: u32) -> Self {
-        Self {
+    pub fn new_with_size(ll: Point, w: usize, h: usize) -> Self {
+        Self::new(
             ll,
-            ur: Point {
-                x: ll.x + w,
-                y: ll.y + h,
+            Point {
+                x: ll.x + w as i64,
+                y: ll.y + h as i64,
             },
+        )
+    }
+
+    pub fn new_polygon(vertices: Vec<[f32; 2]>) -> Self {
+        Self {
+            region: Region::Polygon(vertices),
             resolution: 10,
         }
     }
+
+    pub fn with_resolution(self, resolution: usize) -> Self {
+        Self { resolution, ..self }
+    }
+
+    pub fn resolution(&self) -> usize {
+        self.resolution
+    }
+
+    pub fn contains(&self, p: &Point) -> bool {
+        self.region.contains(p)
+    }
+
+    /// Grid points of the box that are not in any of the `exclude` regions.
+    /// Grid points are the multiples of the resolution, wherever the box
+    /// starts.
+    pub fn points<'a>(&'a self, exclude: &'a [Region]) -> impl Iterator<Item = Point> + 'a {
+        let (ll, ur) = self.region.bounds();
+        let r = self.resolution as i64;
+        let align = move |v: i64| -(-v).div_euclid(r) * r;
+        (align(ll.x)..ur.x)
+            .step_by(self.resolution)
+            .flat_map(move |x| {
+                (align(ll.y)..ur.y)
+                    .step_by(self.resolution)
+                    .map(move |y| Point { x, y })
+            })
+            .filter(move |p| self.region.contains(p) && !exclude.iter().any(|r| r.contains(p)))
+    }
+}
+
+/// Rectangle given by its lower left and upper right corners, either as a
+/// table or as the pair `[[x0, y0], [x1, y1]]`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RectBuilder {
+    ll: [i64; 2],
+    ur: [i64; 2],
+    #[serde(default)]
+    resolution: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct PolygonBuilder {
+    vertices: Vec<[f32; 2]>,
+    #[serde(default)]
+    resolution: Option<usize>,
+}
+
+#[derive(Debug)]
+enum RegionBuilder {
+    Rect(RectBuilder),
+    Polygon(PolygonBuilder),
+}
+
+impl TryFrom<toml::Value> for RegionBuilder {
+    type Error = toml::de::Error;
+
+    /// Tables with `vertices` are polygons, everything else is a rectangle.
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        let polygon = value
+            .as_table()
+            .is_some_and(|table| table.contains_key("vertices"));
+        if polygon {
+            value.try_into().map(RegionBuilder::Polygon)
+        } else {
+            value.try_into().map(RegionBuilder::Rect)
+        }
+    }
 }
 
-pub fn populate_points(point_map: &mut PointMap, boxes: &[AugmentBox], config: &Config) {
-    for AugmentBox { ll, ur, resolution } in boxes {
-        let x_range = (ll.x..ur.x).step_by(*resolution as usize);
-        let y_range = (ll.y..ur.y).step_by(*resolution as usize);
-        for x in x_range {
-            for y in y_range.clone() {
-                point_map.insert_if_absent(Point { x, y }, vec![f32::NAN; config.led_count]);
+impl RegionBuilder {
+    fn build(self, default_resolution: usize) -> Result<AugmentBox, Box<dyn std::error::Error>> {
+        let (region, resolution) = match self {
+            RegionBuilder::Rect(RectBuilder { ll, ur, resolution }) => (
+                Region::Rect {
+                    ll: Point::from(&ll),
+                    ur: Point::from(&ur),
+                },
+                resolution,
+            ),
+            RegionBuilder::Polygon(PolygonBuilder {
+                vertices,
+                resolution,
+            }) => {
+                if vertices.len() < 3 {
+                    return Err("augment polygon needs at least 3 vertices".into());
+                }
+                (Region::Polygon(vertices), resolution)
             }
+        };
+        let resolution = resolution.unwrap_or(default_resolution);
+        if resolution == 0 {
+            return Err("augment resolution must be positive".into());
         }
+        Ok(AugmentBox { region, resolution })
     }
 }
 
-pub fn augment_point(
-    point: &Point,
-    point_map: &PointMap,
+#[derive(Deserialize, Debug)]
+pub struct AugmentBuilder {
+    resolution: Option<usize>,
+    boxes: Option<Vec<toml::Value>>,
+    polygons: Option<Vec<PolygonBuilder>>,
+    exclude: Option<Vec<toml::Value>>,
+}
+
+impl AugmentBuilder {
+    pub fn build(self) -> Result<(Vec<AugmentBox>, Vec<Region>), Box<dyn std::error::Error>> {
+        let resolution = self.resolution.unwrap_or(10);
+        let regions = |values: Option<Vec<toml::Value>>, key: &str| {
+            values
+                .into_iter()
+                .flatten()
+                .enumerate()
+                .map(|(k, value)| {
+                    RegionBuilder::try_from(value)
+                        .map_err(|e| format!("augment.{}[{}]: {}", key, k, e.message()))?
+                        .build(resolution)
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut boxes = regions(self.boxes, "boxes")?;
+        for polygon in self.polygons.into_iter().flatten() {
+            boxes.push(RegionBuilder::Polygon(polygon).build(resolution)?);
+        }
+        let exclude = regions(self.exclude, "exclude")?
+            .into_iter()
+            .map(|b| b.region)
+            .collect();
+        Ok((boxes, exclude))
+    }
+}
+
+/// Adds a record without values for every point of `boxes` outside of
+/// `exclude` that has no record yet.
+pub fn populate_points(
+    records: &mut Vec<RssRecord>,
+    boxes: &[AugmentBox],
+    exclude: &[Region],
     config: &Config,
-    min_pts: usize,
-) -> RssArr {
-    let mut rss = if !point_map.contains(point) {
-        return vec![f32::NAN; config.led_count];
-    } else {
-        let rss_vec = point_map.rss_of(point);
-        assert_eq!(rss_vec.len(), 1);
-        rss_vec[0].clone()
-    };
-    let neighbors = point_map.in_range(point, config.augm_dist);
+) {
+    let mut known = records.iter().map(|r| r.point).collect::<HashSet<_>>();
+    for p in boxes.iter().flat_map(|b| b.points(exclude)) {
+        if known.insert(p) {
+            records.push(RssRecord::new(p, vec![f32::NAN; config.led_count]));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AugmentStrategy {
+    /// Extrapolate from measured neighbors using the Lambertian channel ratio
+    Neighbor,
+    /// Predict missing values from the fitted per-LED channel model
+    Model,
+    /// Model prediction corrected by the residuals of nearby measurements
+    Hybrid,
+}
+
+/// Command line overrides of the augmentation settings.
+#[derive(Debug, Clone, Copy)]
+pub struct AugmentOptions {
+    /// Overrides the configured interpolation method
+    pub strategy: Option<AugmentStrategy>,
+    pub max_iters: usize,
+    pub tolerance: f32,
+    /// Re-estimates augmented values as their neighborhood fills in
+    pub refine: bool,
+}
+
+/// A method of filling missing RSS values from the surrounding values in an `RssMap`.
+pub trait Augmenter {
+    /// Estimates the RSS of LED `led_idx` at `point` and its uncertainty, or
+    /// `None` if there is not enough information to do so.
+    fn augment_led(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f32, Uncertainty)>;
+
+    /// Provenance of the values filled by this augmenter.
+    fn provenance(&self) -> Provenance {
+        Provenance::Augmented(1)
+    }
+
+    /// Fills the missing values of `point`. Only the newly filled values carry
+    /// an uncertainty.
+    fn augment_point(&self, point: &Point, point_map: &RssMap, config: &Config) -> RssRecord {
+        let Some(rss) = current_rss(point, point_map) else {
+            return RssRecord::new(*point, vec![f32::NAN; config.led_count]);
+        };
+        self.augment_rss(point, rss, point_map, config)
+    }
+
+    /// Fills the non-finite entries of `rss`, the values at `point`.
+    fn augment_rss(
+        &self,
+        point: &Point,
+        rss: RssArr,
+        point_map: &RssMap,
+        config: &Config,
+    ) -> RssRecord {
+        let mut record = RssRecord::new(*point, rss);
+        let neighbors = point_map.in_range(point, config.augm_dist);
+
+        for i in 0..config.led_count {
+            // If the RSS value is already computed, skip it
+            if record.rss[i].is_finite() {
+                continue;
+            }
+            if !in_fov(point, i, config) {
+                record.rss[i] = config.fov_outside.value();
+                record.uncertainty[i] = Some(Uncertainty { std: 0.0, n: 0 });
+                record.provenance[i] = Provenance::OutOfFov;
+                continue;
+            }
+            if let Some((value, uncertainty)) = self.augment_led(point, &neighbors, i, config) {
+                record.rss[i] = value;
+                record.uncertainty[i] = Some(uncertainty);
+                record.provenance[i] = self.provenance();
+            }
+        }
+        record
+    }
+}
+
+/// Values at `point`, `None` if it has no record. Several records of the
+/// point are averaged over their finite values.
+pub fn current_rss(point: &Point, point_map: &RssMap) -> Option<RssArr> {
+    match point_map.values(point) {
+        [] => None,
+        [rss] => Some(rss.clone()),
+        records => Some(
+            (0..records[0].len())
+                .map(|i| {
+                    let (sum, n) = records
+                        .iter()
+                        .map(|rss| rss[i])
+                        .filter(|v| v.is_finite())
+                        .fold((0.0, 0), |(sum, n), v| (sum + v, n + 1));
+                    if n > 0 {
+                        sum / n as f32
+                    } else {
+                        f32::NAN
+                    }
+                })
+                .collect(),
+        ),
+    }
+}
 
-    for i in 0..config.led_count {
-        // If the RSS value is already computed, skip it
-        if rss[i].is_finite() {
+/// Weighted mean of `(value, weight)` pairs and the weighted standard
+/// deviation around it, accumulated with West's weighted variant of
+/// Welford's algorithm.
+pub fn weighted_estimate(values: impl IntoIterator<Item = (f32, f32)>) -> (f32, Uncertainty) {
+    let (mut w_sum, mut mean, mut sq_dev, mut n) = (0.0_f64, 0.0_f64, 0.0_f64, 0_usize);
+    for (v, w) in values {
+        n += 1;
+        if w == 0.0 {
             continue;
         }
-        let (aug_sum, aug_cnt) = neighbors
+        let (v, w) = (v as f64, w as f64);
+        w_sum += w;
+        let delta = v - mean;
+        mean += delta * w / w_sum;
+        sq_dev += w * delta * (v - mean);
+    }
+    if w_sum == 0.0 {
+        return (f32::NAN, Uncertainty { std: 0.0, n });
+    }
+    let var = (sq_dev / w_sum).max(0.0);
+    (
+        mean as f32,
+        Uncertainty {
+            std: var.sqrt() as f32,
+            n,
+        },
+    )
+}
+
+/// Extrapolates each neighbor's RSS to the target through the ratio of the
+/// Lambertian channel gains and averages the results.
+pub struct LambertianRatio {
+    pub min_pts: usize,
+}
+
+impl Augmenter for LambertianRatio {
+    fn augment_led(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f32, Uncertainty)> {
+        let i = led_idx;
+        let contributions = neighbors
             .iter()
             .filter_map(|(p_src, rss_src)| {
-                if rss_src[i].is_nan() {
+                if rss_src[i].is_nan() || config.fov_reject_sources && !in_fov(p_src, i, config) {
                     None
                 } else {
-                    Some(compute_augmentation(rss_src[i], p_src, i, point, config))
+                    compute_augmentation(rss_src[i], p_src, i, point, config)
                 }
             })
-            .fold((0.0, 0_usize), |(sum, count), rss| (sum + rss, count + 1));
-        if aug_cnt < min_pts {
-            continue;
+            .collect::<Vec<_>>();
+        if contributions.len() < self.min_pts {
+            return None;
+        }
+        Some(weighted_estimate(
+            contributions.into_iter().map(|v| (v, 1.0)),
+        ))
+    }
+}
+
+/// Predicts missing values from the per-LED channel model, optionally
+/// corrected by the residuals of nearby measurements.
+pub struct ChannelModel {
+    pub residual_correction: bool,
+}
+
+impl Augmenter for ChannelModel {
+    fn augment_led(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f32, Uncertainty)> {
+        let i = led_idx;
+        let prediction = predict_rss(point, i, config);
+        if !self.residual_correction {
+            let uncertainty = Uncertainty {
+                std: f32::NAN,
+                n: 0,
+            };
+            return Some((prediction, uncertainty));
+        }
+        let residuals = neighbors
+            .iter()
+            .filter(|(_, rss_src)| rss_src[i].is_finite())
+            .map(|(p_src, rss_src)| rss_src[i] - predict_rss(p_src, i, config))
+            .filter(|res| res.is_finite())
+            .collect::<Vec<_>>();
+        if residuals.is_empty() {
+            let uncertainty = Uncertainty {
+                std: f32::NAN,
+                n: 0,
+            };
+            return Some((prediction, uncertainty));
         }
+        let (correction, uncertainty) = weighted_estimate(residuals.iter().map(|&r| (r, 1.0)));
+        // Shrink the correction towards the pure model when few neighbors are available
+        let weight = uncertainty.n as f32 / (uncertainty.n + config.hybrid_shrinkage) as f32;
+        Some((prediction + weight * correction, uncertainty))
+    }
 
-        rss[i] = aug_sum / aug_cnt as f32;
+    fn provenance(&self) -> Provenance {
+        Provenance::Predicted
     }
-    rss
 }
 
-pub fn augment_all_points(
-    to_augment: &[Point],
-    point_map: &PointMap,
+pub fn augment_point(
+    point: &Point,
+    point_map: &RssMap,
+    config: &Config,
+    min_pts: usize,
+) -> RssRecord {
+    LambertianRatio { min_pts }.augment_point(point, point_map, config)
+}
+
+/// Repeatedly augments the points of `point_map` until no new values are
+/// filled and no augmented value changes by more than the tolerance.
+///
+/// Each round only revisits the points near values that changed in the
+/// previous round, and writes the changed points back to `point_map`.
+/// Augmented values are final unless `options.refine` is set, in which case
+/// they are re-estimated as their neighborhood fills in. Measured values are
+/// never modified. The provenance and uncertainty of the existing values are
+/// taken from `prior`.
+pub fn augment_iteratively(
+    mut point_map: RssMap,
+    prior: &[RssRecord],
+    augmenter: &(dyn Augmenter + Sync),
     config: &Config,
+    options: &AugmentOptions,
+    pb: &ProgressBar,
 ) -> Vec<RssRecord> {
-    to_augment
+    let points = point_map.points();
+    let index = points
+        .iter()
+        .enumerate()
+        .map(|(k, p)| (*p, k))
+        .collect::<HashMap<_, _>>();
+    let mut records = points
         .iter()
-        .map(|p| RssRecord {
-            point: *p,
-            rss: augment_point(p, point_map, config, config.augm_min_neighbors),
+        .map(|p| {
+            let rss =
+                current_rss(p, &point_map).unwrap_or_else(|| vec![f32::NAN; config.led_count]);
+            RssRecord::new(*p, rss)
+        })
+        .collect::<Vec<_>>();
+    for r in prior {
+        if let Some(&k) = index.get(&r.point) {
+            records[k].provenance.clone_from(&r.provenance);
+            records[k].uncertainty.clone_from(&r.uncertainty);
+            point_map[r.point] = vec![source_rss(&records[k])];
+        }
+    }
+    let refine = options.refine;
+    let needs_augmentation = |record: &RssRecord| {
+        record
+            .rss
+            .iter()
+            .zip(&record.uncertainty)
+            .any(|(v, u)| v.is_nan() || (refine && u.is_some()))
+    };
+    let mut frontier = (0..records.len())
+        .filter(|&k| needs_augmentation(&records[k]))
+        .collect::<Vec<_>>();
+
+    for it in 0..options.max_iters {
+        if frontier.is_empty() {
+            break;
+        }
+        pb.reset();
+        pb.set_length(frontier.len() as u64);
+        let updates = frontier
+            .par_iter()
+            .progress_with(pb.clone())
+            .map(|&k| {
+                let record = &records[k];
+                let rss = if refine {
+                    // Augmented values are estimated again from the current neighborhood
+                    record
+                        .rss
+                        .iter()
+                        .zip(&record.uncertainty)
+                        .map(|(&v, u)| if u.is_some() { f32::NAN } else { v })
+                        .collect()
+                } else {
+                    record.rss.clone()
+                };
+                (
+                    k,
+                    augmenter.augment_rss(&record.point, rss, &point_map, config),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut filled = 0;
+        let mut max_change = 0.0_f32;
+        let mut changed = Vec::new();
+        for (k, new) in updates {
+            let record = &mut records[k];
+            let mut modified = false;
+            let mut point_changed = false;
+            for i in 0..config.led_count {
+                let (old, value) = (record.rss[i], new.rss[i]);
+                let measured = old.is_finite() && record.uncertainty[i].is_none();
+                if measured || value.is_nan() || value == old {
+                    continue;
+                }
+                if old.is_nan() {
+                    filled += 1;
+                    point_changed = true;
+                    record.provenance[i] = match new.provenance[i] {
+                        Provenance::Augmented(_) => Provenance::Augmented(it as u32 + 1),
+                        p => p,
+                    };
+                } else {
+                    let change = (value - old).abs();
+                    max_change = max_change.max(change);
+                    point_changed |= change > options.tolerance;
+                }
+                modified = true;
+                record.rss[i] = value;
+                record.uncertainty[i] = new.uncertainty[i];
+            }
+            if modified {
+                point_map[record.point] = vec![source_rss(record)];
+            }
+            if point_changed {
+                changed.push(record.point);
+            }
+        }
+        // A hidden bar drops its `println`s, e.g. when stderr is not a terminal
+        pb.suspend(|| {
+            eprintln!(
+                "Augmentation iteration {}: {} points visited, {} values filled, max change {}",
+                it + 1,
+                frontier.len(),
+                filled,
+                max_change
+            )
+        });
+        if filled == 0 && max_change <= options.tolerance {
+            break;
+        }
+
+        let mut next = HashSet::new();
+        for p in &changed {
+            for (q, _) in point_map.in_range(p, config.augm_dist) {
+                if let Some(&k) = index.get(&q) {
+                    if needs_augmentation(&records[k]) {
+                        next.insert(k);
+                    }
+                }
+            }
+        }
+        frontier = next.into_iter().collect();
+        frontier.sort_unstable();
+    }
+    records
+}
+
+/// Values of `record` usable as augmentation sources. Values set outside of
+/// an LED's field of view were never lit by it, so they are left out.
+pub fn source_rss(record: &RssRecord) -> RssArr {
+    record
+        .rss
+        .iter()
+        .zip(&record.provenance)
+        .map(|(&v, p)| {
+            if *p == Provenance::OutOfFov {
+                f32::NAN
+            } else {
+                v
+            }
         })
         .collect()
 }
 
-fn point_led_distance(&Point { x: x1, y: y1 }: &Point, led_idx: usize, config: &Config) -> f32 {
-    let Point { x: x2, y: y2 } = config.led_positions[led_idx];
-    let dx = x1 as f32 - x2 as f32;
-    let dy = y1 as f32 - y2 as f32;
-    let dz = config.height as f32;
-    (dx * dx + dy * dy + dz * dz).sqrt()
+/// RSS predicted by the channel model of LED `led_idx` at `point`.
+pub fn predict_rss(point: &Point, led_idx: usize, config: &Config) -> f32 {
+    predict_rss_at(
+        &Position::from(point),
+        config.receiver_height,
+        led_idx,
+        config,
+    )
+}
+
+/// RSS predicted by the channel model of LED `led_idx` for a receiver at
+/// `position` and `receiver_height`.
+pub fn predict_rss_at(
+    position: &Position,
+    receiver_height: f32,
+    led_idx: usize,
+    config: &Config,
+) -> f32 {
+    let v = receiver_led_vector(position, receiver_height, led_idx, config);
+    let d = dot(v, v).sqrt();
+    let (cos_emit, cos_incid) = vector_angles(v, led_idx, config);
+    if cos_emit.acos() > config.led_fovs[led_idx] || cos_incid <= 0.0 {
+        return config.fov_outside.value();
+    }
+    config.led_powers[led_idx]
+        * config.propagation[led_idx].gain(d)
+        * cos_emit.powf(config.lambertian_orders[led_idx])
+        * cos_incid
+}
+
+pub(crate) fn in_fov(point: &Point, led_idx: usize, config: &Config) -> bool {
+    let (cos_emit, _) = led_angles(point, led_idx, config);
+    cos_emit.acos() <= config.led_fovs[led_idx]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Vector from the receiver at `point` to the LED.
+fn led_vector(point: &Point, led_idx: usize, config: &Config) -> [f32; 3] {
+    receiver_led_vector(
+        &Position::from(point),
+        config.receiver_height,
+        led_idx,
+        config,
+    )
+}
+
+fn receiver_led_vector(
+    &Position { x, y }: &Position,
+    receiver_height: f32,
+    led_idx: usize,
+    config: &Config,
+) -> [f32; 3] {
+    let Position { x: lx, y: ly } = config.led_positions[led_idx];
+    [
+        lx - x,
+        ly - y,
+        config.led_heights[led_idx] - receiver_height,
+    ]
+}
+
+fn point_led_distance(point: &Point, led_idx: usize, config: &Config) -> f32 {
+    let v = led_vector(point, led_idx, config);
+    dot(v, v).sqrt()
 }
 
+/// Cosines of the emission (irradiance) angle at the LED and the incidence
+/// angle at the receiver.
+fn led_angles(point: &Point, led_idx: usize, config: &Config) -> (f32, f32) {
+    vector_angles(led_vector(point, led_idx, config), led_idx, config)
+}
+
+fn vector_angles(v: [f32; 3], led_idx: usize, config: &Config) -> (f32, f32) {
+    let d = dot(v, v).sqrt();
+    let cos_emit = -dot(v, config.led_normals[led_idx]) / d;
+    let cos_incid = dot(v, config.receiver_normal) / d;
+    (cos_emit, cos_incid)
+}
+
+/// Extrapolates `rss`, measured at `src`, to `tgt`. `None` if the source's
+/// channel gain is zero, as it is facing away from the LED, so that the
+/// ratio of the gains is undefined.
 fn compute_augmentation(
     rss: f32,
     src: &Point,
     led_idx: usize,
     tgt: &Point,
     config: &Config,
-) -> f32 {
+) -> Option<f32> {
     let d_src = point_led_distance(src, led_idx, config);
     let d_tgt = point_led_distance(tgt, led_idx, config);
-    let a_src = (config.prop_loss_func)(d_src);
-    let a_tgt = (config.prop_loss_func)(d_tgt);
-    let cos_src = (config.height as f32) / d_src;
-    let cos_tgt = (config.height as f32) / d_tgt;
-    // If the target point is outside the LED's field of view, return 0
-    // if cos_tgt.acos() > config.led_fov {
-    //     return 0.0;
-    // }
-    rss * a_tgt / a_src * (cos_tgt / cos_src).powf(config.lambertian_order + 1.0)
+    let a_src = config.propagation[led_idx].gain(d_src);
+    let a_tgt = config.propagation[led_idx].gain(d_tgt);
+    let (emit_src, incid_src) = led_angles(src, led_idx, config);
+    let (emit_tgt, incid_tgt) = led_angles(tgt, led_idx, config);
+    // If the target point is outside the LED's field of view, it receives no light
+    if emit_tgt.acos() > config.led_fovs[led_idx] || incid_tgt <= 0.0 {
+        return Some(config.fov_outside.value());
+    }
+    if emit_src <= 0.0 || incid_src <= 0.0 || a_src <= 0.0 {
+        return None;
+    }
+    let m = config.lambertian_orders[led_idx];
+    let value = rss * a_tgt / a_src * (emit_tgt / emit_src).powf(m) * (incid_tgt / incid_src);
+    value.is_finite().then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mean of the other points' values in range.
+    struct NeighborMean;
+
+    impl Augmenter for NeighborMean {
+        fn augment_led(
+            &self,
+            point: &Point,
+            neighbors: &[(Point, &RssArr)],
+            led_idx: usize,
+            _config: &Config,
+        ) -> Option<(f32, Uncertainty)> {
+            let values = neighbors
+                .iter()
+                .filter(|(q, rss)| q != point && rss[led_idx].is_finite())
+                .map(|(_, rss)| (rss[led_idx], 1.0));
+            let (mean, uncertainty) = weighted_estimate(values);
+            mean.is_finite().then_some((mean, uncertainty))
+        }
+    }
+
+    /// Augments a row of four points with measured ends 0 and 3.
+    fn augment_row(refine: bool) -> Vec<f32> {
+        let config = Config {
+            augm_dist: 10,
+            ..Config::default()
+        };
+        let records = [0.0, f32::NAN, f32::NAN, 3.0]
+            .iter()
+            .enumerate()
+            .map(|(k, &v)| {
+                let mut rss = vec![f32::NAN; config.led_count];
+                rss[0] = v;
+                RssRecord::new(Point::new(10 * k as i64, 0), rss)
+            })
+            .collect::<Vec<_>>();
+        let options = AugmentOptions {
+            strategy: None,
+            max_iters: 50,
+            tolerance: 1e-4,
+            refine,
+        };
+        augment_iteratively(
+            RssMap::from_records(&records, 10),
+            &records,
+            &NeighborMean,
+            &config,
+            &options,
+            &ProgressBar::hidden(),
+        )
+        .iter()
+        .map(|r| r.rss[0])
+        .collect()
+    }
+
+    #[test]
+    fn augmented_values_are_final_by_default() {
+        assert_eq!(augment_row(false), vec![0.0, 0.0, 3.0, 3.0]);
+    }
+
+    #[test]
+    fn refinement_reestimates_augmented_values() {
+        let row = augment_row(true);
+        assert!((row[1] - 1.0).abs() < 1e-2, "{:?}", row);
+        assert!((row[2] - 2.0).abs() < 1e-2, "{:?}", row);
+    }
+
+    #[test]
+    fn current_rss_averages_duplicate_records() {
+        let p = Point::new(0, 0);
+        let records = vec![
+            RssRecord::new(p, vec![1.0, f32::NAN, f32::NAN]),
+            RssRecord::new(p, vec![3.0, 2.0, f32::NAN]),
+        ];
+        let rss = current_rss(&p, &RssMap::from_records(&records, 1)).unwrap();
+        assert_eq!(rss[..2], [2.0, 2.0]);
+        assert!(rss[2].is_nan());
+    }
+
+    #[test]
+    fn hybrid_correction_is_shrunk_by_its_own_setting() {
+        let config = Config {
+            hybrid_shrinkage: 1,
+            augm_min_neighbors: 100,
+            ..Config::default()
+        };
+        let (p, q) = (Point::new(250, 250), Point::new(260, 250));
+        let mut rss = vec![f32::NAN; config.led_count];
+        rss[0] = predict_rss(&q, 0, &config) + 0.5;
+        let (value, _) = ChannelModel {
+            residual_correction: true,
+        }
+        .augment_led(&p, &[(q, &rss)], 0, &config)
+        .unwrap();
+        // One residual against a shrinkage of one gives half the correction
+        assert!((value - predict_rss(&p, 0, &config) - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weighted_spread_survives_a_large_offset() {
+        let (mean, uncertainty) =
+            weighted_estimate([(1e4 + 1.0, 1.0), (1e4 - 1.0, 1.0), (1e4 + 1.0, 2.0)]);
+        assert!((mean - (1e4 + 0.5)).abs() < 1e-3);
+        assert!((uncertainty.std - 0.75_f32.sqrt()).abs() < 1e-4);
+        assert_eq!(uncertainty.n, 3);
+    }
+
+    #[test]
+    fn values_outside_the_field_of_view_carry_an_uncertainty() {
+        let config = Config {
+            led_fovs: vec![0.1; Config::default().led_count],
+            ..Config::default()
+        };
+        let p = (0..100)
+            .map(|k| Point::new(100 * k, 0))
+            .find(|p| !in_fov(p, 0, &config))
+            .unwrap();
+        let map = RssMap::from_records(&[], 1);
+        let record = NeighborMean.augment_rss(&p, vec![f32::NAN; config.led_count], &map, &config);
+        assert_eq!(record.rss[0], config.fov_outside.value());
+        assert_eq!(record.uncertainty[0], Some(Uncertainty { std: 0.0, n: 0 }));
+        assert_eq!(record.provenance[0], Provenance::OutOfFov);
+        assert_eq!(record.provenance[0].to_string(), "out_of_fov");
+    }
+
+    #[test]
+    fn values_outside_the_field_of_view_are_not_sources() {
+        for fov_reject_sources in [false, true] {
+            let config = Config {
+                led_positions: vec![Position::new(0.0, 0.0)],
+                led_fovs: vec![0.1],
+                augm_dist: 30,
+                grid_resolution: 10,
+                fov_reject_sources,
+                ..Config::default().select_leds(&[0])
+            };
+            let mut records = vec![RssRecord::new(Point::new(0, 0), vec![1.0])];
+            let row = AugmentBox::new(Point::new(-300, 0), Point::new(310, 1));
+            populate_points(&mut records, &[row], &[], &config);
+            let options = AugmentOptions {
+                strategy: None,
+                max_iters: 50,
+                tolerance: 1e-4,
+                refine: false,
+            };
+            let records = augment_iteratively(
+                RssMap::from_records(&records, 10),
+                &records,
+                &LambertianRatio { min_pts: 1 },
+                &config,
+                &options,
+                &ProgressBar::hidden(),
+            );
+            let near = records
+                .iter()
+                .find(|r| r.point == Point::new(10, 0))
+                .unwrap();
+            assert!((near.rss[0] - 1.0).abs() < 0.01, "{:?}", near);
+            // Every point within the cone is lit, every one outside is not
+            for r in &records {
+                if in_fov(&r.point, 0, &config) {
+                    assert!(r.rss[0] > 0.8, "{:?}", r);
+                } else {
+                    assert_eq!(r.provenance[0], Provenance::OutOfFov);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extrapolation_follows_the_configured_propagation_model() {
+        let extrapolate = |text: &str| {
+            let config = Config::from_toml(text, std::path::Path::new(".")).unwrap();
+            let led = config.led_positions[0];
+            let src = Point::new(led.x as i64, led.y as i64);
+            let tgt = Point::new(src.x + 1000, src.y);
+            let ratio = point_led_distance(&tgt, 0, &config) / point_led_distance(&src, 0, &config);
+            (
+                compute_augmentation(1.0, &src, 0, &tgt, &config).unwrap(),
+                ratio,
+            )
+        };
+        let (square, ratio) = extrapolate("led_count = 1");
+        let (cube, _) =
+            extrapolate("led_count = 1\n[propagation]\nmodel = \"inverse_power\"\nexponent = 3.0");
+        // The angles are the same, only the extra power of the distance differs
+        assert!((cube / square - 1.0 / ratio).abs() < 1e-4);
+    }
+
+    #[test]
+    fn sources_without_channel_gain_are_not_extrapolated() {
+        let led_count = Config::default().led_count;
+        let tilt = std::f32::consts::FRAC_1_SQRT_2;
+        let config = Config {
+            led_normals: vec![[tilt, 0.0, -tilt]; led_count],
+            led_fovs: vec![std::f32::consts::FRAC_PI_2; led_count],
+            ..Config::default()
+        };
+        let led = config.led_positions[0];
+        let (x, y) = (led.x as i64, led.y as i64);
+        let tgt = Point::new(x, y);
+        // Far behind a LED tilted sideways, the source gets no light
+        let behind = Point::new(x - 10_000, y);
+        assert!(led_angles(&behind, 0, &config).0 <= 0.0);
+        assert_eq!(compute_augmentation(1.0, &behind, 0, &tgt, &config), None);
+        let value = compute_augmentation(1.0, &Point::new(x + 50, y), 0, &tgt, &config);
+        assert!(value.is_some_and(|v| v.is_finite() && v > 0.0));
+    }
+
+    fn augment_table(text: &str) -> Result<(Vec<AugmentBox>, Vec<Region>), String> {
+        toml::from_str::<AugmentBuilder>(text)
+            .map_err(|e| e.to_string())?
+            .build()
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn regions_are_read_by_their_keys() {
+        let (boxes, exclude) = augment_table(
+            "boxes = [[[0, 0], [10, 10]], { ll = [0, 0], ur = [20, 20], resolution = 5 }, \
+             { vertices = [[0, 0], [10, 0], [0, 10]] }]\n\
+             exclude = [{ vertices = [[0, 0], [1, 0], [0, 1]] }]",
+        )
+        .unwrap();
+        assert_eq!(
+            boxes.iter().map(|b| b.resolution).collect::<Vec<_>>(),
+            [10, 5, 10]
+        );
+        assert!(matches!(boxes[2].region, Region::Polygon(_)));
+        assert!(matches!(exclude[..], [Region::Polygon(_)]));
+    }
+
+    #[test]
+    fn misspelled_region_keys_are_reported() {
+        let err =
+            augment_table("boxes = [{ ll = [0, 0], ur = [10, 10], resoluton = 5 }]").unwrap_err();
+        assert!(err.contains("augment.boxes[0]"), "{}", err);
+        assert!(err.contains("unknown field `resoluton`"), "{}", err);
+        let err = augment_table("exclude = [{ vertices = [[0, 0], [1, 0], [0, 1]], ll = [0, 0] }]")
+            .unwrap_err();
+        assert!(err.contains("unknown field `ll`"), "{}", err);
+    }
+
+    #[test]
+    fn box_points_are_multiples_of_the_resolution() {
+        let points = AugmentBox::new(Point::new(5, -5), Point::new(35, 15))
+            .points(&[])
+            .map(|p| (p.x, p.y))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            points,
+            [(10, 0), (10, 10), (20, 0), (20, 10), (30, 0), (30, 10)]
+        );
+    }
 }
@@ -0,0 +1,349 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    path::Path,
+};
+
+use crate::{
+    augment::AugmentOptions,
+    augment_records,
+    config::Config,
+    evaluate::{Key, Split},
+    open_output,
+    point::Point,
+    point_map::{PointMap, PointMapConfig},
+    rss_record::{read_records, RssArr, RssRecord},
+};
+
+/// Errors of the augmented values of one LED.
+#[derive(Debug, Clone, Copy, Default)]
+struct ErrorStats {
+    /// Hidden values with a ground truth
+    count: usize,
+    /// Hidden values filled by the augmentation
+    filled: usize,
+    sum: f32,
+    sq_sum: f32,
+    /// Sum of the relative errors, over the values with a non-zero truth
+    rel_sum: f32,
+    rel_count: usize,
+}
+
+impl ErrorStats {
+    fn add(&mut self, truth: f32, estimate: f32) {
+        self.count += 1;
+        if !estimate.is_finite() {
+            return;
+        }
+        let error = estimate - truth;
+        self.filled += 1;
+        self.sum += error;
+        self.sq_sum += error * error;
+        if truth != 0.0 {
+            self.rel_sum += (error / truth).abs();
+            self.rel_count += 1;
+        }
+    }
+
+    fn rmse(&self) -> Option<f32> {
+        (self.filled > 0).then(|| (self.sq_sum / self.filled as f32).sqrt())
+    }
+
+    fn bias(&self) -> Option<f32> {
+        (self.filled > 0).then(|| self.sum / self.filled as f32)
+    }
+
+    fn rel_error(&self) -> Option<f32> {
+        (self.rel_count > 0).then(|| self.rel_sum / self.rel_count as f32)
+    }
+
+    /// Fields of a report line. The errors are empty if no hidden value was
+    /// filled, the relative error also if no filled value has a non-zero
+    /// truth.
+    fn fields(&self) -> [String; 5] {
+        let field = |v: Option<f32>| v.map_or_else(String::new, |v| v.to_string());
+        [
+            self.count.to_string(),
+            self.filled.to_string(),
+            field(self.rmse()),
+            field(self.bias()),
+            field(self.rel_error()),
+        ]
+    }
+}
+
+/// Mean of the finite values of each LED over all records of each measured
+/// point, the ground truth of the benchmark.
+fn ground_truth(records: &[RssRecord], led_count: usize) -> BTreeMap<Key, RssArr> {
+    let mut sums = BTreeMap::<Key, Vec<(f32, usize)>>::new();
+    for record in records {
+        let sum = sums
+            .entry((record.room.clone(), record.point))
+            .or_insert_with(|| vec![(0.0, 0); led_count]);
+        for (s, v) in sum.iter_mut().zip(&record.rss) {
+            if v.is_finite() {
+                s.0 += v;
+                s.1 += 1;
+            }
+        }
+    }
+    sums.into_iter()
+        .map(|(key, sum)| {
+            let mean = sum
+                .into_iter()
+                .map(|(s, n)| if n > 0 { s / n as f32 } else { f32::NAN })
+                .collect();
+            (key, mean)
+        })
+        .collect()
+}
+
+/// Distance from `p` to the closest point of `remaining`, searched within
+/// doubling radii from the map resolution. `remaining` must hold a point.
+fn nearest_distance(p: &Point, remaining: &PointMap<Option<()>>, resolution: usize) -> f32 {
+    let mut r = resolution;
+    loop {
+        let nearest = remaining
+            .within_radius(*p, r)
+            .into_iter()
+            .filter(|(_, v)| v.is_some())
+            .map(|(q, _)| p.dist_sq(&q))
+            .min();
+        if let Some(d) = nearest {
+            return (d as f32).sqrt();
+        }
+        r *= 2;
+    }
+}
+
+/// Errors per LED, and per LED and distance bin.
+#[derive(Debug, Default)]
+struct Scores {
+    bin_width: usize,
+    totals: Vec<ErrorStats>,
+    by_distance: BTreeMap<(usize, usize), ErrorStats>,
+}
+
+impl Scores {
+    fn new(led_count: usize, bin_width: usize) -> Self {
+        Self {
+            bin_width,
+            totals: vec![ErrorStats::default(); led_count],
+            by_distance: BTreeMap::new(),
+        }
+    }
+
+    /// Augments the records of one room, whose `hidden` points have no
+    /// values, and scores the refilled values against `truth`. Rooms
+    /// without any remaining measurement are skipped, hidden points that
+    /// the augmentation leaves out count as unfilled.
+    fn add_room(
+        &mut self,
+        room: &Option<String>,
+        records: Vec<RssRecord>,
+        hidden: &HashSet<Key>,
+        truth: &BTreeMap<Key, RssArr>,
+        config: &Config,
+        options: &AugmentOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let config = config.for_room(room.as_deref())?;
+        let remaining = records
+            .iter()
+            .filter(|r| !hidden.contains(&(room.clone(), r.point)))
+            .map(|r| r.point)
+            .collect::<Vec<_>>();
+        if remaining.is_empty() {
+            eprintln!(
+                "Skipping room {}: all of its points are hidden",
+                room.as_deref().unwrap_or("-")
+            );
+            return Ok(());
+        }
+        let resolution = config.grid_resolution;
+        let mut remaining_map = PointMap::with_occupancy(
+            PointMapConfig::bounding(&remaining, resolution),
+            remaining.len(),
+        );
+        for &p in &remaining {
+            remaining_map.insert(p, Some(()));
+        }
+        let augmented = augment_records(records, config, options)?
+            .into_iter()
+            .map(|r| (r.point, r.rss))
+            .collect::<HashMap<_, _>>();
+        for (key_room, p) in hidden {
+            if key_room != room {
+                continue;
+            }
+            let bin = nearest_distance(p, &remaining_map, resolution) as usize / self.bin_width;
+            let estimate = augmented.get(p);
+            for (led, &t) in truth[&(room.clone(), *p)].iter().enumerate() {
+                let e = estimate
+                    .and_then(|rss| rss.get(led))
+                    .copied()
+                    .unwrap_or(f32::NAN);
+                if t.is_finite() {
+                    self.totals[led].add(t, e);
+                    self.by_distance.entry((led, bin)).or_default().add(t, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Scores one split round: every measured point of `truth` becomes a
+    /// record, without values if it is `hidden`, and each room is augmented
+    /// and scored on its own.
+    fn add_round(
+        &mut self,
+        truth: &BTreeMap<Key, RssArr>,
+        hidden: &HashSet<Key>,
+        config: &Config,
+        options: &AugmentOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut rooms = BTreeMap::<_, Vec<_>>::new();
+        for ((room, p), rss) in truth {
+            let rss = if hidden.contains(&(room.clone(), *p)) {
+                vec![f32::NAN; rss.len()]
+            } else {
+                rss.clone()
+            };
+            rooms
+                .entry(room.clone())
+                .or_default()
+                .push(RssRecord::new(*p, rss).with_room(room.clone()));
+        }
+        for (room, room_records) in rooms {
+            self.add_room(&room, room_records, hidden, truth, config, options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hides the values of the test points of each split round, refills them by
+/// augmentation and compares the result with the hidden values. Per-LED
+/// errors are reported on stderr, errors by distance to the nearest
+/// remaining measurement are written to `output`.
+pub fn run(
+    input: &Path,
+    output: Option<&Path>,
+    split: &Split,
+    bin_width: usize,
+    options: &AugmentOptions,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let records = read_records(input, config)?;
+    let truth = ground_truth(&records, config.led_count);
+    let keys = truth.keys().cloned().collect::<Vec<_>>();
+    if keys.len() < 2 {
+        return Err("at least two measured points are needed for a benchmark".into());
+    }
+
+    let mut scores = Scores::new(config.led_count, bin_width);
+    for hidden in split.test_sets(&keys) {
+        scores.add_round(&truth, &hidden, config, options)?;
+    }
+
+    eprintln!("led,count,filled,rmse,bias,rel_error");
+    for (led, stats) in scores.totals.iter().enumerate() {
+        eprintln!("{},{}", led, stats.fields().join(","));
+    }
+
+    let mut wtr = csv::Writer::from_writer(open_output(output)?);
+    wtr.write_record([
+        "led",
+        "distance",
+        "count",
+        "filled",
+        "rmse",
+        "bias",
+        "rel_error",
+    ])?;
+    for ((led, bin), stats) in &scores.by_distance {
+        let mut row = vec![led.to_string(), (bin * bin_width).to_string()];
+        row.extend(stats.fields());
+        wtr.write_record(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpolate::InterpolationMethod;
+
+    fn scores_of(hidden: &[Key], config: &Config) -> Result<Scores, Box<dyn Error>> {
+        let mut truth = BTreeMap::new();
+        for room in ["a", "b"] {
+            for x in 0..3 {
+                for y in 0..3 {
+                    truth.insert(
+                        (Some(room.to_owned()), Point::new(10 * x, 10 * y)),
+                        vec![1.0],
+                    );
+                }
+            }
+        }
+        let hidden = hidden.iter().cloned().collect::<HashSet<_>>();
+        let options = AugmentOptions {
+            strategy: None,
+            max_iters: 1,
+            tolerance: 0.0,
+            refine: false,
+        };
+        let mut scores = Scores::new(1, 10);
+        scores.add_round(&truth, &hidden, config, &options)?;
+        Ok(scores)
+    }
+
+    fn config() -> Config {
+        let room = Config {
+            led_count: 1,
+            interpolation: InterpolationMethod::Idw { power: 2.0 },
+            augment_boxes: Vec::new(),
+            ..Config::default()
+        };
+        Config {
+            rooms: [
+                ("a".to_owned(), room.clone()),
+                ("b".to_owned(), room.clone()),
+            ]
+            .into(),
+            ..room
+        }
+    }
+
+    #[test]
+    fn rooms_without_remaining_measurements_are_skipped() {
+        let mut hidden = (0..3)
+            .flat_map(|x| (0..3).map(move |y| (Some("a".to_owned()), Point::new(10 * x, 10 * y))))
+            .collect::<Vec<_>>();
+        hidden.push((Some("b".to_owned()), Point::new(10, 10)));
+        let scores = scores_of(&hidden, &config()).unwrap();
+        assert_eq!(scores.totals[0].count, 1);
+        assert_eq!(scores.totals[0].filled, 1);
+        assert!(scores.totals[0].rmse().unwrap() < 1e-6);
+        assert_eq!(scores.by_distance.keys().collect::<Vec<_>>(), [&(0, 1)]);
+    }
+
+    #[test]
+    fn rooms_are_augmented_with_their_own_configuration() {
+        let mut config = config();
+        config.rooms.remove("b");
+        assert!(scores_of(&[], &config).is_err());
+    }
+
+    #[test]
+    fn missing_estimates_count_as_unfilled() {
+        let mut stats = ErrorStats::default();
+        stats.add(1.0, f32::NAN);
+        assert_eq!(stats.fields().join(","), "1,0,,,");
+        stats.add(1.0, 2.0);
+        assert_eq!((stats.count, stats.filled), (2, 1));
+        assert_eq!(stats.rmse(), Some(1.0));
+        let mut stats = ErrorStats::default();
+        stats.add(0.0, 1.0);
+        assert_eq!(stats.fields().join(","), "1,1,1,1,");
+    }
+}
@@ -0,0 +1,313 @@
+use std::{error::Error, io::Write, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    config::{read_table, room_table, set_key, Config},
+    fit::{levenberg_marquardt, Fit},
+    open_output,
+    propagation::PropagationModel,
+    rss_record::{by_room, read_records, RssRecord},
+};
+
+/// Per-LED channel parameters in the format read by `Config::from_file`,
+/// written over the keys of the configuration file.
+#[derive(Serialize, Debug)]
+struct Calibration {
+    led_positions: Vec<[f32; 3]>,
+    led_powers: Vec<f32>,
+    lambertian_orders: Vec<f32>,
+    path_loss_exponents: Vec<f32>,
+}
+
+/// Fitted parameter vector: `[ln(power), lambertian order, x, y, path-loss exponent]`.
+pub const PARAM_COUNT: usize = 5;
+
+fn led_model(params: &[f64], x: f64, y: f64, led_idx: usize, config: &Config) -> f64 {
+    let &[ln_power, order, lx, ly, exponent] = params else {
+        unreachable!()
+    };
+    let v = [
+        lx - x,
+        ly - y,
+        (config.led_heights[led_idx] - config.receiver_height) as f64,
+    ];
+    let d = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let n_led = config.led_normals[led_idx];
+    let n_rx = config.receiver_normal;
+    let cos_emit = -(v[0] * n_led[0] as f64 + v[1] * n_led[1] as f64 + v[2] * n_led[2] as f64) / d;
+    let cos_incid = (v[0] * n_rx[0] as f64 + v[1] * n_rx[1] as f64 + v[2] * n_rx[2] as f64) / d;
+    if cos_emit <= 0.0 || cos_incid <= 0.0 {
+        return 0.0;
+    }
+    // Negative orders and exponents are not physical
+    ln_power.exp() * d.powf(-exponent.max(0.0)) * cos_emit.powf(order.max(0.0)) * cos_incid
+}
+
+fn initial_params(samples: &[(f64, f64, f64)], led_idx: usize, config: &Config) -> Vec<f64> {
+    let pos = config.led_positions[led_idx];
+    let mut params = vec![
+        0.0,
+        config.lambertian_orders[led_idx] as f64,
+        pos.x as f64,
+        pos.y as f64,
+        2.0,
+    ];
+    // The power enters the model linearly, so its best value for the other
+    // initial parameters has a closed form.
+    let (num, den) = samples.iter().fold((0.0, 0.0), |(num, den), &(x, y, rss)| {
+        let f = led_model(&params, x, y, led_idx, config);
+        (num + f * rss, den + f * f)
+    });
+    if num > 0.0 && den > 0.0 {
+        params[0] = (num / den).ln();
+    }
+    params
+}
+
+/// `(x, y, rss)` of every record with a value for LED `led_idx`.
+pub fn led_samples(records: &[RssRecord], led_idx: usize) -> Vec<(f64, f64, f64)> {
+    records
+        .iter()
+        .filter(|r| r.rss[led_idx].is_finite())
+        .map(|r| (r.point.x as f64, r.point.y as f64, r.rss[led_idx] as f64))
+        .collect()
+}
+
+pub fn fit_led(
+    samples: &[(f64, f64, f64)],
+    led_idx: usize,
+    max_iters: usize,
+    config: &Config,
+) -> Fit {
+    let residuals = |params: &[f64]| {
+        samples
+            .iter()
+            .map(|&(x, y, rss)| led_model(params, x, y, led_idx, config) - rss)
+            .collect()
+    };
+    levenberg_marquardt(
+        residuals,
+        initial_params(samples, led_idx, config),
+        max_iters,
+    )
+}
+
+/// Number of samples and fit of an LED, `None` if it had too few samples.
+type LedFit = Option<(usize, Fit)>;
+
+/// Configured path-loss exponent of every LED. Only inverse-power models can
+/// be fitted, their exponent is one of the fitted parameters.
+fn path_loss_exponents(config: &Config) -> Result<Vec<f32>, String> {
+    config
+        .propagation
+        .iter()
+        .enumerate()
+        .map(|(i, model)| match model {
+            PropagationModel::InversePower { exponent } => Ok(*exponent),
+            _ => Err(format!(
+                "LED {} has no inverse-power propagation model, the only one that can be calibrated",
+                i
+            )),
+        })
+        .collect()
+}
+
+/// Fits the channel parameters of every LED to `records`. LEDs with too few
+/// samples to fit keep their configured parameters.
+fn calibrate(
+    records: &[RssRecord],
+    max_iters: usize,
+    config: &Config,
+) -> Result<(Calibration, Vec<LedFit>), String> {
+    let exponents = path_loss_exponents(config)?;
+    let mut calibration = Calibration {
+        led_positions: Vec::with_capacity(config.led_count),
+        led_powers: Vec::with_capacity(config.led_count),
+        lambertian_orders: Vec::with_capacity(config.led_count),
+        path_loss_exponents: Vec::with_capacity(config.led_count),
+    };
+    let mut fits = Vec::with_capacity(config.led_count);
+    for (i, &exponent) in exponents.iter().enumerate() {
+        let samples = led_samples(records, i);
+        if samples.len() <= PARAM_COUNT {
+            eprintln!(
+                "LED {} has {} samples, too few to fit, keeping its configured parameters",
+                i,
+                samples.len()
+            );
+            let position = config.led_positions[i];
+            calibration
+                .led_positions
+                .push([position.x, position.y, config.led_heights[i]]);
+            calibration.led_powers.push(config.led_powers[i]);
+            calibration
+                .lambertian_orders
+                .push(config.lambertian_orders[i]);
+            calibration.path_loss_exponents.push(exponent);
+            fits.push(None);
+            continue;
+        }
+        let fit = fit_led(&samples, i, max_iters, config);
+        let params = &fit.params;
+        calibration
+            .led_positions
+            .push([params[2] as f32, params[3] as f32, config.led_heights[i]]);
+        calibration.led_powers.push(params[0].exp() as f32);
+        calibration
+            .lambertian_orders
+            .push(params[1].max(0.0) as f32);
+        calibration
+            .path_loss_exponents
+            .push(params[4].max(0.0) as f32);
+        fits.push(Some((samples.len(), fit)));
+    }
+    Ok((calibration, fits))
+}
+
+fn report(
+    records: &[RssRecord],
+    calibration: &Calibration,
+    fits: &[Option<(usize, Fit)>],
+    config: &Config,
+) {
+    eprintln!("led,samples,iterations,rmse,rel_rmse,dx,dy");
+    for (i, fit) in fits.iter().enumerate() {
+        let Some((samples, fit)) = fit else {
+            eprintln!("{},0,0,NaN,NaN,NaN,NaN", i);
+            continue;
+        };
+        let mean = records
+            .iter()
+            .map(|r| r.rss[i])
+            .filter(|rss| rss.is_finite())
+            .sum::<f32>() as f64
+            / *samples as f64;
+        let [x, y, _] = calibration.led_positions[i];
+        let configured = config.led_positions[i];
+        eprintln!(
+            "{},{},{},{},{},{},{}",
+            i,
+            samples,
+            fit.iterations,
+            fit.rmse(),
+            fit.rmse() / mean,
+            x - configured.x,
+            y - configured.y,
+        );
+    }
+}
+
+/// Sets the calibrated parameters in the configuration `table`.
+fn set_calibration(
+    table: &mut toml::Table,
+    calibration: &Calibration,
+) -> Result<(), Box<dyn Error>> {
+    let toml::Value::Table(calibrated) = toml::Value::try_from(calibration)? else {
+        unreachable!()
+    };
+    for (key, value) in calibrated {
+        set_key(table, &key, value);
+    }
+    Ok(())
+}
+
+/// Calibrates the LEDs of each room with the room's configuration and writes
+/// the configuration file at `config_path` with the calibrated parameters in
+/// place, the parameters of each room in its `[rooms.<id>]` table.
+pub fn run(
+    input: &Path,
+    output: Option<&Path>,
+    max_iters: usize,
+    config_path: Option<&Path>,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut table = read_table(config_path)?;
+    for (room, records) in by_room(read_records(input, config)?) {
+        if let Some(room) = &room {
+            eprintln!("Calibrating room {}", room);
+        }
+        let room_config = config.for_room(room.as_deref())?;
+        let (calibration, fits) = calibrate(&records, max_iters, room_config)?;
+        report(&records, &calibration, &fits, room_config);
+        set_calibration(room_table(&mut table, room.as_deref())?, &calibration)?;
+    }
+    let mut output = open_output(output)?;
+    output.write_all(toml::to_string(&table)?.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point::Point;
+
+    #[test]
+    fn output_is_a_complete_configuration() {
+        let mut table = "led_count = 1\nreceiver_height = 500.0\nlambertian_order = 3.0\n"
+            .parse::<toml::Table>()
+            .unwrap();
+        let calibration = Calibration {
+            led_positions: vec![[10.0, 20.0, 1760.0]],
+            led_powers: vec![2.0],
+            lambertian_orders: vec![1.5],
+            path_loss_exponents: vec![2.1],
+        };
+        set_calibration(&mut table, &calibration).unwrap();
+
+        let config = Config::from_toml(&toml::to_string(&table).unwrap(), Path::new(".")).unwrap();
+        assert_eq!(config.receiver_height, 500.0);
+        assert_eq!(config.lambertian_orders, vec![1.5]);
+        assert_eq!(config.led_powers, vec![2.0]);
+        assert!(config.calibrated_powers);
+    }
+
+    #[test]
+    fn leds_with_too_few_samples_keep_their_parameters() {
+        let config = Config::default();
+        let records = (0..3)
+            .map(|k| {
+                let mut rss = vec![f32::NAN; config.led_count];
+                rss[0] = 1e-7;
+                RssRecord::new(Point::new(100 * k, 0), rss)
+            })
+            .collect::<Vec<_>>();
+        let (calibration, fits) = calibrate(&records, 10, &config).unwrap();
+        assert!(fits[0].is_none());
+        assert_eq!(calibration.led_powers[0], config.led_powers[0]);
+        assert_eq!(
+            calibration.lambertian_orders[0],
+            config.lambertian_orders[0]
+        );
+        assert_eq!(calibration.path_loss_exponents[0], 2.0);
+    }
+
+    #[test]
+    fn fitted_orders_and_exponents_are_not_negative() {
+        let config = Config::default();
+        // Flat readings pull the order and exponent below zero
+        let records = (0..10)
+            .flat_map(|x| (0..10).map(move |y| Point::new(200 * x, 200 * y)))
+            .map(|p| {
+                let mut rss = vec![f32::NAN; config.led_count];
+                rss[0] = 1e-6 * (1.0 + (p.x + p.y) as f32 / 1000.0);
+                RssRecord::new(p, rss)
+            })
+            .collect::<Vec<_>>();
+        let (calibration, _) = calibrate(&records, 200, &config).unwrap();
+        assert!(calibration.lambertian_orders[0] >= 0.0);
+        assert!(calibration.path_loss_exponents[0] >= 0.0);
+    }
+
+    #[test]
+    fn only_inverse_power_models_are_calibrated() {
+        let config = Config::from_toml(
+            "led_count = 1\n[propagation]\nmodel = \"two_slope\"\n\
+             breakpoint = 2.0\nexponent_near = 1.0\nexponent_far = 3.0",
+            Path::new("."),
+        )
+        .unwrap();
+        let records = vec![RssRecord::new(Point::new(0, 0), vec![1.0])];
+        assert!(calibrate(&records, 10, &config).is_err());
+    }
+}
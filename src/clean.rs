@@ -1,61 +1,132 @@
+use std::collections::HashMap;
+
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::augment::augment_point;
+use crate::augment::{augment_point, predict_rss, source_rss};
 use crate::config::{pb_style, Config};
 use crate::point::Point;
-use crate::point_map::PointMap;
-use crate::rss_record::{RssArr, RssRecord};
+use crate::point_map::RssMap;
+use crate::rss_record::{Provenance, RssArr, RssRecord};
 
-pub fn clean_records_stg1(raw_records: Vec<RssRecord>, config: &Config) -> Vec<RssRecord> {
-    let point_map = PointMap::from_raw_records(raw_records);
-    let points = point_map.all_points();
+/// Cleans the raw records, keeping the best candidate per point and LED.
+/// Returns the audit of every candidate if `audit` is set.
+pub fn clean_records_stg1(
+    raw_records: Vec<RssRecord>,
+    config: &Config,
+    audit: bool,
+) -> (Vec<RssRecord>, Vec<CandidateAudit>) {
+    let point_map = RssMap::from_records(&raw_records, config.grid_resolution);
+    // Weights of the candidates of each point, in the order of the map's values
+    let mut weights = HashMap::<_, Vec<_>>::new();
+    for record in &raw_records {
+        weights.entry(record.point).or_default().push(record.weight);
+    }
+    let points = point_map.points();
     let stg1 = indicatif::ProgressBar::new(points.len() as u64).with_style(pb_style());
     stg1.set_message("Cleaning data (stage 1)");
     let stg1 = points
         .par_iter()
         .progress_with(stg1)
         .map(|&p| {
-            let rss = clean_point(&p, &point_map, config);
-            RssRecord { point: p, rss }
+            let (rss, audits) = clean_point(&p, &point_map, &weights[&p], config, audit);
+            let mut record = RssRecord::new(p, rss);
+            if point_map.values(&p).len() > 1 {
+                for (prov, rss) in record.provenance.iter_mut().zip(&record.rss) {
+                    if rss.is_finite() {
+                        *prov = Provenance::Selected;
+                    }
+                }
+            }
+            (record, audits)
         })
         .collect::<Vec<_>>();
-    stg1
+    let (records, audits): (Vec<_>, Vec<_>) = stg1.into_iter().unzip();
+    (records, audits.into_iter().flatten().collect())
 }
 
+/// Fills the missing values of the cleaned `raw_records` from their
+/// neighbors. The existing values keep their provenance and uncertainty.
 pub fn clean_records_stg2(raw_records: Vec<RssRecord>, config: &Config) -> Vec<RssRecord> {
-    let point_map = PointMap::from_raw_records(raw_records);
-    let points = point_map.all_points();
+    let prior = raw_records
+        .iter()
+        .map(|r| (r.point, r))
+        .collect::<HashMap<_, _>>();
+    let point_map = RssMap::with_inferred_bounds(
+        raw_records
+            .iter()
+            .map(|r| (r.point, source_rss(r)))
+            .collect(),
+        config.grid_resolution,
+    );
+    let points = point_map.points();
     let stg2 = indicatif::ProgressBar::new(points.len() as u64).with_style(pb_style());
     stg2.set_message("Cleaning data (stage 2) - itera  tion");
     let stg2 = points
         .par_iter()
         .progress_with(stg2)
         .map(|&p| {
-            let rss = augment_point(&p, &point_map, config, config.augm_min_neighbors);
-            RssRecord { point: p, rss }
+            let mut record = augment_point(&p, &point_map, config, config.augm_min_neighbors);
+            let Some(prev) = prior.get(&p) else {
+                return record;
+            };
+            for i in 0..record.rss.len() {
+                // Only the values filled now carry an uncertainty
+                if record.uncertainty[i].is_none() {
+                    record.provenance[i] = prev.provenance[i];
+                    record.uncertainty[i] = prev.uncertainty[i];
+                } else if let Provenance::Augmented(_) = record.provenance[i] {
+                    record.provenance[i] = Provenance::Filled;
+                }
+            }
+            record
         })
         .collect::<Vec<_>>();
     stg2
 }
 
-pub fn clean_led(point: Point, point_map: &PointMap<Vec<f32>>, config: &Config) -> Option<f32> {
-    let neighbors = point_map
-        .within_radius(point, config.clean_dist as usize)
-        .into_iter()
-        .flatten();
-}
-
 #[derive(Debug, Clone)]
 pub struct RssScore {
     pub rss: f32,
     pub score: f32,
+    pub candidate: usize,
+}
+
+/// Outcome of a candidate value during stage 1 of cleaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Chosen,
+    /// The continuity score exceeded the LED's continuity threshold
+    AboveThreshold,
+    /// Another candidate had a lower score
+    Outscored,
+    /// The score could not be computed
+    NoScore,
+    /// The value is missing or not finite
+    NotFinite,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidateAudit {
+    pub x: i64,
+    pub y: i64,
+    pub led: usize,
+    pub candidate: usize,
+    pub rss: f32,
+    pub score: f32,
+    pub threshold: f32,
+    pub verdict: Verdict,
 }
 
 #[derive(Debug)]
 pub struct CleanRecord {
     pub point: Point,
     pub rss: Vec<Option<RssScore>>,
+    /// Whether the candidates are shares of split measurements, which are
+    /// all chosen if within the threshold
+    pub shares: bool,
 }
 
 impl CleanRecord {
@@ -63,80 +134,416 @@ impl CleanRecord {
         CleanRecord {
             point: p,
             rss: vec![None; config.led_count],
+            shares: false,
         }
     }
 
-    fn update(&mut self, rss: &RssArr, scores: &[f32], config: &Config) {
+    fn update(&mut self, candidate: usize, rss: &RssArr, scores: &[f32], thresholds: &[f32]) {
         self.rss
             .iter_mut()
             .zip(rss)
-            .zip(scores)
-            .for_each(|((r, &rss), &score)| {
-                if score > config.continuity_thresh {
+            .zip(scores.iter().zip(thresholds))
+            .for_each(|((r, &rss), (&score, &threshold))| {
+                // A missing value or score never beats a real measurement
+                if !rss.is_finite() || !score.is_finite() || score > threshold {
                     return;
                 }
+                let new = RssScore {
+                    rss,
+                    score,
+                    candidate,
+                };
                 if let Some(old) = r {
                     if old.score > score {
-                        *r = Some(RssScore { rss, score });
+                        *r = Some(new);
                     }
                 } else {
-                    *r = Some(RssScore { rss, score });
+                    *r = Some(new);
                 }
             });
     }
+
+    fn audit(
+        &self,
+        candidates: &[RssArr],
+        scores: &[Vec<f32>],
+        thresholds: &[f32],
+    ) -> Vec<CandidateAudit> {
+        let mut audits = Vec::new();
+        for (k, (rss, scores)) in candidates.iter().zip(scores).enumerate() {
+            for (led, ((&rss, &score), &threshold)) in
+                rss.iter().zip(scores).zip(thresholds).enumerate()
+            {
+                let chosen = if self.shares {
+                    rss.is_finite() && score <= threshold
+                } else {
+                    self.rss[led].as_ref().is_some_and(|r| r.candidate == k)
+                };
+                let verdict = if chosen {
+                    Verdict::Chosen
+                } else if !rss.is_finite() {
+                    Verdict::NotFinite
+                } else if !score.is_finite() {
+                    Verdict::NoScore
+                } else if score > threshold {
+                    Verdict::AboveThreshold
+                } else {
+                    Verdict::Outscored
+                };
+                audits.push(CandidateAudit {
+                    x: self.point.x,
+                    y: self.point.y,
+                    led,
+                    candidate: k,
+                    rss,
+                    score,
+                    threshold,
+                    verdict,
+                });
+            }
+        }
+        audits
+    }
 }
 
-fn clean_point(p: &Point, point_map: &PointMap, config: &Config) -> RssArr {
-    let neighbors = point_map.rss_in_range(p, config.clean_dist);
-    let continuity_scorer = ContinuityScorer::new(&neighbors, config);
-    let candidates = point_map.rss_of(p);
-    let clean_record = candidates
+/// Cleans the candidates of `p`. If some of them are shares of split
+/// measurements, all candidates within the threshold are averaged by their
+/// `weights`, otherwise the best candidate is kept.
+fn clean_point(
+    p: &Point,
+    point_map: &RssMap,
+    weights: &[f32],
+    config: &Config,
+    audit: bool,
+) -> (RssArr, Vec<CandidateAudit>) {
+    let neighbors = point_map
+        .in_range(p, config.clean_dist)
+        .into_iter()
+        .map(|(_, rss)| rss)
+        .collect::<Vec<_>>();
+    let continuity_scorer = ContinuityScorer::new(p, &neighbors, config);
+    let candidates = point_map.values(p);
+    let scores = candidates
         .iter()
-        .fold(CleanRecord::new(*p, config), |mut record, rss| {
-            let scores = continuity_scorer.compute(rss);
-            record.update(rss, &scores, config);
+        .map(|rss| continuity_scorer.compute(rss))
+        .collect::<Vec<_>>();
+    let thresholds = &continuity_scorer.thresholds;
+    let mut clean_record = candidates.iter().zip(&scores).enumerate().fold(
+        CleanRecord::new(*p, config),
+        |mut record, (k, (rss, scores))| {
+            record.update(k, rss, scores, thresholds);
             record
-        });
-    clean_record
-        .rss
-        .into_iter()
-        .map(|r| r.map_or(f32::NAN, |r| r.rss))
-        .collect()
+        },
+    );
+    clean_record.shares = weights.iter().any(|&w| w != 1.0);
+    let audits = if audit {
+        clean_record.audit(candidates, &scores, thresholds)
+    } else {
+        Vec::new()
+    };
+    let rss = if clean_record.shares {
+        (0..config.led_count)
+            .map(|i| {
+                let (sum, weight) = candidates
+                    .iter()
+                    .zip(&scores)
+                    .zip(weights)
+                    .filter(|((rss, scores), _)| rss[i].is_finite() && scores[i] <= thresholds[i])
+                    .fold((0.0, 0.0), |(s, w), ((rss, _), &weight)| {
+                        (s + weight * rss[i], w + weight)
+                    });
+                if weight > 0.0 {
+                    sum / weight
+                } else {
+                    f32::NAN
+                }
+            })
+            .collect()
+    } else {
+        clean_record
+            .rss
+            .into_iter()
+            .map(|r| r.map_or(f32::NAN, |r| r.rss))
+            .collect()
+    };
+    (rss, audits)
+}
+
+fn default_hampel_k() -> f32 {
+    3.0
+}
+
+fn default_trim() -> f32 {
+    0.1
+}
+
+/// How the expected value of a point is estimated from its neighborhood and
+/// how deviations from it are scored.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum ScoreMethod {
+    /// Absolute deviation from the neighborhood mean, in RSS units
+    Mean,
+    /// Deviation from the neighborhood median in units of the scaled MAD
+    MedianMad,
+    /// Deviation from the median in units of `k` scaled MADs, so that a
+    /// threshold of 1 is the classic Hampel filter
+    Hampel {
+        #[serde(default = "default_hampel_k")]
+        k: f32,
+    },
+    /// Absolute deviation from the mean with the `trim` fraction of the
+    /// lowest and highest values removed on each side, in RSS units
+    TrimmedMean {
+        #[serde(default = "default_trim")]
+        trim: f32,
+    },
+}
+
+/// Scale factor making the MAD a consistent estimator of the standard deviation.
+const MAD_SCALE: f32 = 1.4826;
+
+/// Median of the `sorted` values, NaN if there are none.
+pub fn median(sorted: &[f32]) -> f32 {
+    let n = sorted.len();
+    if n == 0 {
+        return f32::NAN;
+    }
+    if n % 2 == 1 {
+        sorted[n / 2]
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+    }
+}
+
+/// What the per-LED continuity thresholds are relative to.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdMode {
+    /// Thresholds are in units of the score, which differ per `ScoreMethod`
+    Absolute,
+    /// Thresholds are fractions of the neighborhood's expected value
+    Neighbors,
+    /// Thresholds are fractions of the value predicted by the channel model,
+    /// which needs calibrated `led_powers`
+    Model,
 }
 
 struct ContinuityScorer {
-    neighbor_rss_avg: RssArr,
-    darkness_penalty: f32,
+    center: RssArr,
+    scale: RssArr,
+    darkness_penalties: Vec<f32>,
+    thresholds: Vec<f32>,
 }
 
 impl ContinuityScorer {
-    fn new(neighbors_rss: &[&RssArr], config: &Config) -> Self {
-        let sums = neighbors_rss
-            .iter()
-            .fold(vec![0.0; config.led_count], |sum, rss| {
-                sum.iter()
-                    .zip(rss.iter())
-                    .map(|(s, r)| s + r)
-                    .collect::<RssArr>()
-            });
-        let avg = sums
-            .into_iter()
-            .map(|s| s / neighbors_rss.len() as f32)
-            .collect();
+    fn new(point: &Point, neighbors_rss: &[&RssArr], config: &Config) -> Self {
+        let (center, scale): (RssArr, RssArr) = (0..config.led_count)
+            .map(|i| {
+                // Neighbors without a value for this LED do not contribute
+                let mut values = neighbors_rss
+                    .iter()
+                    .map(|rss| rss[i])
+                    .filter(|v| v.is_finite())
+                    .collect::<Vec<_>>();
+                values.sort_by(f32::total_cmp);
+                Self::estimate(config.score_method, &values)
+            })
+            .unzip();
+        let thresholds = Self::thresholds(point, &center, &scale, config);
         ContinuityScorer {
-            neighbor_rss_avg: avg,
-            darkness_penalty: config.darkness_penalty,
+            center,
+            scale,
+            darkness_penalties: config.darkness_penalties.clone(),
+            thresholds,
+        }
+    }
+
+    /// Score thresholds at `point`. Relative thresholds whose reference value
+    /// is unavailable fall back to being absolute.
+    fn thresholds(point: &Point, center: &[f32], scale: &[f32], config: &Config) -> Vec<f32> {
+        (0..config.led_count)
+            .map(|i| {
+                let threshold = config.continuity_thresholds[i];
+                let reference = match config.threshold_mode {
+                    ThresholdMode::Absolute => return threshold,
+                    ThresholdMode::Neighbors => center[i],
+                    ThresholdMode::Model => predict_rss(point, i, config),
+                };
+                if !reference.is_finite() {
+                    return threshold;
+                }
+                // Scores are deviations in units of the scale
+                threshold * reference.abs() / scale[i]
+            })
+            .collect()
+    }
+
+    /// Center and scale of the sorted finite `values`.
+    fn estimate(method: ScoreMethod, values: &[f32]) -> (f32, f32) {
+        let mean = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+        let mad_scale = |center: f32| {
+            let mut deviations = values
+                .iter()
+                .map(|v| (v - center).abs())
+                .collect::<Vec<_>>();
+            deviations.sort_by(f32::total_cmp);
+            (MAD_SCALE * median(&deviations)).max(1e-6)
+        };
+        match method {
+            ScoreMethod::Mean => (mean(values), 1.0),
+            ScoreMethod::MedianMad => {
+                let center = median(values);
+                (center, mad_scale(center))
+            }
+            ScoreMethod::Hampel { k } => {
+                let center = median(values);
+                (center, k * mad_scale(center))
+            }
+            ScoreMethod::TrimmedMean { trim } => {
+                let cut = (values.len() as f32 * trim.clamp(0.0, 0.49)) as usize;
+                (mean(&values[cut..values.len() - cut]), 1.0)
+            }
         }
     }
 
     fn compute(&self, rss: &RssArr) -> Vec<f32> {
-        let f = |(val, avg)| {
-            if val > avg {
-                (val - avg) / self.darkness_penalty
+        let f = |(((val, center), scale), penalty): (((&f32, &f32), &f32), &f32)| {
+            let deviation = if val > center {
+                (val - center) / penalty
             } else {
-                avg - val
-            }
+                center - val
+            };
+            deviation / scale
+        };
+        rss.iter()
+            .zip(&self.center)
+            .zip(&self.scale)
+            .zip(&self.darkness_penalties)
+            .map(f)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::point::Position;
+
+    /// Center of `candidates`, whose records are added to four neighbors
+    /// measuring 10 for a single LED.
+    pub const CENTER: Point = Point { x: 10, y: 10 };
+
+    /// Configuration with a threshold of 6 for the single LED, and the
+    /// records of the four neighbors of `CENTER` followed by `candidates`.
+    pub fn candidates(candidates: Vec<RssRecord>) -> (Config, Vec<RssRecord>) {
+        let config = Config {
+            continuity_thresholds: vec![6.0],
+            darkness_penalties: vec![1.0],
+            clean_dist: 10,
+            grid_resolution: 10,
+            ..Config::default().select_leds(&[0])
+        };
+        let mut records = [(0, 10), (10, 0), (20, 10), (10, 20)]
+            .into_iter()
+            .map(|(x, y)| RssRecord::new(Point::new(x, y), vec![10.0]))
+            .collect::<Vec<_>>();
+        records.extend(candidates);
+        (config, records)
+    }
+
+    #[test]
+    fn split_shares_are_averaged_after_cleaning() {
+        let p = CENTER;
+        let (config, records) = candidates(
+            [(10.0, 0.5), (11.0, 0.25), (40.0, 0.25)]
+                .into_iter()
+                .map(|(v, w)| RssRecord::new(p, vec![v]).with_weight(w))
+                .collect(),
+        );
+        let (cleaned, audits) = clean_records_stg1(records, &config, true);
+        let cleaned = cleaned.iter().find(|r| r.point == p).unwrap();
+        // The outlying share is dropped before the others are averaged
+        assert_eq!(cleaned.rss, [(0.5 * 10.0 + 0.25 * 11.0) / 0.75]);
+        let verdicts = audits
+            .iter()
+            .filter(|a| (a.x, a.y) == (10, 10))
+            .map(|a| a.verdict)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            verdicts,
+            [Verdict::Chosen, Verdict::Chosen, Verdict::AboveThreshold]
+        );
+    }
+
+    #[test]
+    fn missing_candidates_never_win() {
+        for values in [[f32::NAN, 11.0, 12.0], [11.0, f32::NAN, 12.0]] {
+            let (config, records) = candidates(
+                values
+                    .into_iter()
+                    .map(|v| RssRecord::new(CENTER, vec![v]))
+                    .collect(),
+            );
+            let (cleaned, _) = clean_records_stg1(records, &config, false);
+            let cleaned = cleaned.iter().find(|r| r.point == CENTER).unwrap();
+            assert_eq!(cleaned.rss, [11.0], "{:?}", values);
+        }
+    }
+
+    #[test]
+    fn filled_values_keep_their_provenance_and_uncertainty() {
+        let config = Config {
+            led_positions: vec![Position::new(0.0, 0.0)],
+            led_fovs: vec![0.1],
+            augm_dist: 30,
+            augm_min_neighbors: 1,
+            grid_resolution: 10,
+            ..Config::default().select_leds(&[0])
+        };
+        let (hole, dark) = (Point::new(10, 0), Point::new(300, 0));
+        let mut records = (-10..=30)
+            .map(|k| {
+                let p = Point::new(10 * k, 0);
+                let v = if p == hole || p == dark {
+                    f32::NAN
+                } else {
+                    1.0
+                };
+                RssRecord::new(p, vec![v])
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..2 {
+            records = clean_records_stg2(records, &config);
+        }
+        let record = |p: Point| records.iter().find(|r| r.point == p).unwrap();
+        assert_eq!(record(dark).provenance[0], Provenance::OutOfFov);
+        assert_eq!(record(dark).provenance[0].to_string(), "out_of_fov");
+        assert!(record(dark).uncertainty[0].is_some());
+        assert_eq!(record(hole).provenance[0], Provenance::Filled);
+        assert!(record(hole).uncertainty[0].is_some_and(|u| u.n > 0));
+        let measured = record(Point::new(0, 0));
+        assert_eq!(measured.provenance[0], Provenance::Raw);
+        assert!(measured.uncertainty[0].is_none());
+    }
+
+    #[test]
+    fn absolute_scores_are_in_the_unit_of_the_method() {
+        let neighbors = [9.0, 10.0, 10.0, 11.0, 12.0];
+        let neighbors = neighbors.iter().map(|&v| vec![v]).collect::<Vec<_>>();
+        let neighbors = neighbors.iter().collect::<Vec<_>>();
+        let score = |score_method| {
+            let config = Config {
+                score_method,
+                darkness_penalties: vec![1.0],
+                ..Config::default().select_leds(&[0])
+            };
+            ContinuityScorer::new(&Point::new(0, 0), &neighbors, &config).compute(&vec![13.0])[0]
         };
-        rss.iter().zip(&self.neighbor_rss_avg).map(f).collect()
+        // 13 is 2.6 above the mean and 3 above the median, whose MAD is 1
+        assert!((score(ScoreMethod::Mean) - 2.6).abs() < 1e-5);
+        assert!((score(ScoreMethod::TrimmedMean { trim: 0.2 }) - (13.0 - 31.0 / 3.0)).abs() < 1e-5);
+        assert!((score(ScoreMethod::MedianMad) - 3.0 / MAD_SCALE).abs() < 1e-5);
+        assert!((score(ScoreMethod::Hampel { k: 2.0 }) - 1.5 / MAD_SCALE).abs() < 1e-5);
     }
 }
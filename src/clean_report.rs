@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, error::Error, fs::File, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    clean::{CandidateAudit, Verdict},
+    config::Config,
+    point::Point,
+};
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct Summary {
+    scope: &'static str,
+    id: String,
+    candidates: usize,
+    chosen: usize,
+    above_threshold: usize,
+    outscored: usize,
+    no_score: usize,
+    not_finite: usize,
+    /// Fraction of candidates rejected by the continuity threshold
+    rejection_rate: f32,
+}
+
+impl Summary {
+    fn add(&mut self, verdict: Verdict) {
+        self.candidates += 1;
+        match verdict {
+            Verdict::Chosen => self.chosen += 1,
+            Verdict::AboveThreshold => self.above_threshold += 1,
+            Verdict::Outscored => self.outscored += 1,
+            Verdict::NoScore => self.no_score += 1,
+            Verdict::NotFinite => self.not_finite += 1,
+        }
+        self.rejection_rate = self.above_threshold as f32 / self.candidates as f32;
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    summary: Vec<Summary>,
+    candidates: &'a [CandidateAudit],
+}
+
+/// Aggregates the audit per LED and per configured augmentation region.
+fn summarize(audits: &[CandidateAudit], config: &Config) -> Vec<Summary> {
+    let mut per_led = BTreeMap::new();
+    let mut per_region = BTreeMap::new();
+    for audit in audits {
+        per_led
+            .entry(audit.led)
+            .or_insert_with(|| Summary {
+                scope: "led",
+                id: audit.led.to_string(),
+                ..Default::default()
+            })
+            .add(audit.verdict);
+        let p = Point::new(audit.x, audit.y);
+        let region = config.augment_boxes.iter().position(|b| b.contains(&p));
+        per_region
+            .entry(region)
+            .or_insert_with(|| Summary {
+                scope: "region",
+                id: region.map_or_else(|| "none".to_owned(), |r| r.to_string()),
+                ..Default::default()
+            })
+            .add(audit.verdict);
+    }
+    per_led
+        .into_values()
+        .chain(per_region.into_values())
+        .collect()
+}
+
+/// Writes the cleaning audit to `path`. A `.json` file holds both the
+/// candidates and the summary, otherwise the candidates are written as CSV
+/// and the summary to a sibling `.summary.csv` file.
+pub fn write_report(
+    path: &Path,
+    audits: &[CandidateAudit],
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let summary = summarize(audits, config);
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let report = Report {
+            summary,
+            candidates: audits,
+        };
+        serde_json::to_writer_pretty(File::create(path)?, &report)?;
+        return Ok(());
+    }
+
+    let mut wtr = csv::Writer::from_path(path)?;
+    for audit in audits {
+        wtr.serialize(audit)?;
+    }
+    wtr.flush()?;
+    let mut wtr = csv::Writer::from_path(path.with_extension("summary.csv"))?;
+    for s in &summary {
+        wtr.serialize(s)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        augment::AugmentBox,
+        clean::{
+            clean_records_stg1,
+            tests::{candidates, CENTER},
+        },
+        rss_record::RssRecord,
+    };
+
+    #[test]
+    fn rejections_are_audited_and_summarized() {
+        let (config, records) = candidates(
+            [10.0, 11.0, 40.0, f32::NAN]
+                .into_iter()
+                .map(|v| RssRecord::new(CENTER, vec![v]))
+                .collect(),
+        );
+        // The only region holds the center, its neighbors are in none
+        let config = Config {
+            augment_boxes: vec![AugmentBox::new(CENTER, Point::new(20, 20))],
+            ..config
+        };
+        let (_, audits) = clean_records_stg1(records, &config, true);
+        let verdicts = audits
+            .iter()
+            .filter(|a| Point::new(a.x, a.y) == CENTER)
+            .map(|a| a.verdict)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Outscored,
+                Verdict::Chosen,
+                Verdict::AboveThreshold,
+                Verdict::NotFinite
+            ]
+        );
+
+        let summary = summarize(&audits, &config);
+        let counts = summary
+            .iter()
+            .map(|s| {
+                (
+                    s.scope,
+                    s.id.as_str(),
+                    [
+                        s.candidates,
+                        s.chosen,
+                        s.outscored,
+                        s.above_threshold,
+                        s.not_finite,
+                    ],
+                )
+            })
+            .collect::<Vec<_>>();
+        // The outlier at the center also lifts the neighbors above the threshold
+        assert_eq!(
+            counts,
+            [
+                ("led", "0", [8, 1, 1, 5, 1]),
+                ("region", "none", [4, 0, 0, 4, 0]),
+                ("region", "0", [4, 1, 1, 1, 1]),
+            ]
+        );
+        assert!((summary[2].rejection_rate - 0.25).abs() < 1e-6);
+
+        // CSV reports keep the summary in a sibling file, JSON ones inline
+        let dir = std::env::temp_dir().join("process_data_clean_report_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        write_report(&dir.join("audit.csv"), &audits, &config).unwrap();
+        let rows = |path: &Path| csv::Reader::from_path(path).unwrap().records().count();
+        assert_eq!(rows(&dir.join("audit.csv")), audits.len());
+        assert_eq!(rows(&dir.join("audit.summary.csv")), summary.len());
+        write_report(&dir.join("audit.json"), &audits, &config).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_reader(File::open(dir.join("audit.json")).unwrap()).unwrap();
+        assert_eq!(json["summary"].as_array().unwrap().len(), summary.len());
+        // The candidates of the center follow those of (10, 0) and (0, 10)
+        assert_eq!(json["candidates"][3]["verdict"], "chosen");
+        assert_eq!(json["candidates"][5]["verdict"], "not_finite");
+    }
+}
@@ -1,109 +1,604 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    f32::consts::{FRAC_PI_2, PI},
+    path::Path,
+};
 
 use serde::Deserialize;
 
-use crate::point::Point;
+use crate::augment::{AugmentBox, AugmentBuilder, Region};
+use crate::clean::{ScoreMethod, ThresholdMode};
+use crate::interpolate::InterpolationMethod;
+use crate::led_health::HealthCheck;
+use crate::point::{Point, Position, SnapPolicy, Unit};
+use crate::propagation::{PropagationBuilder, PropagationModel};
 
-const fn led_to_point(led: usize) -> Point {
+fn led_to_position(led: usize) -> Position {
     let x = led % 6;
     let y = led / 6;
-    Point {
-        x: x as u32 * 500 + 250,
-        y: y as u32 * 500 + 250,
+    Position {
+        x: (x * 500 + 250) as f32,
+        y: (y * 500 + 250) as f32,
     }
 }
 
+/// Value assigned to targets outside an LED's field of view.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FovPolicy {
+    Zero,
+    Nan,
+}
+
+impl FovPolicy {
+    pub fn value(self) -> f32 {
+        match self {
+            FovPolicy::Zero => 0.0,
+            FovPolicy::Nan => f32::NAN,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct CleanAugmentConfig {
-    pub clean_dist: u32,
-    pub augm_dist: u32,
-    pub continuity_thresh: f32,
+    /// Unit of grid coordinates and of all distances in the configuration
+    pub unit: Unit,
+    /// Unit of the measurement positions in the input
+    pub input_unit: Unit,
+    /// Spacing of the grid measurements are snapped to and of the maps
+    /// holding them. Augment resolutions must be multiples of it.
+    pub grid_resolution: usize,
+    pub snap: SnapPolicy,
+    pub clean_dist: usize,
+    pub augm_dist: usize,
+    /// Largest continuity score of a kept value, per LED. With an absolute
+    /// `threshold_mode` its unit depends on the `score_method`: RSS units
+    /// for `mean` and `trimmed_mean`, scaled MADs for `median_mad` and
+    /// multiples of `k` scaled MADs for `hampel`. With a relative mode it is
+    /// a fraction of the reference value for every method.
+    pub continuity_thresholds: Vec<f32>,
+    pub threshold_mode: ThresholdMode,
     pub led_count: usize,
     pub height: u32,
-    pub led_positions: Vec<Point>,
-    pub half_power_semiangle: f32,
-    pub lambertian_order: f32,
-    pub prop_loss_func: fn(f32) -> f32,
+    pub led_positions: Vec<Position>,
+    pub led_heights: Vec<f32>,
+    pub led_normals: Vec<[f32; 3]>,
+    pub receiver_height: f32,
+    pub receiver_normal: [f32; 3],
+    pub lambertian_orders: Vec<f32>,
+    pub led_powers: Vec<f32>,
+    /// Whether `led_powers` was configured rather than left at the default,
+    /// which the channel model predictions rely on
+    pub calibrated_powers: bool,
+    pub propagation: Vec<PropagationModel>,
     pub augm_min_neighbors: usize,
-    pub darkness_penalty: f32,
-    pub led_fov: f32,
+    /// Number of neighbor residuals at which the hybrid augmentation applies
+    /// half of their mean as a correction to the model prediction
+    pub hybrid_shrinkage: usize,
+    pub darkness_penalties: Vec<f32>,
+    pub score_method: ScoreMethod,
+    pub led_fovs: Vec<f32>,
+    pub fov_outside: FovPolicy,
+    pub fov_reject_sources: bool,
     pub augm_min_neighbors2: usize,
+    pub augm_max_iters: usize,
+    pub augm_tolerance: f32,
+    /// Re-estimates augmented values in later iterations instead of keeping
+    /// them as first filled
+    pub augm_refine: bool,
+    pub augment_boxes: Vec<AugmentBox>,
+    pub augment_exclude: Vec<Region>,
+    pub interpolation: InterpolationMethod,
+    pub led_health: HealthCheck,
+    /// Configuration of each room, for records with a room identifier
+    pub rooms: BTreeMap<String, CleanAugmentConfig>,
+}
+
+fn default_augment_boxes() -> Vec<AugmentBox> {
+    vec![
+        AugmentBox::new_with_size(Point::new(0, 0), 1210, 1210),
+        AugmentBox::new_with_size(Point::new(1610, 0), 1210, 1210),
+        AugmentBox::new_with_size(Point::new(0, 1550), 1210, 1210),
+        AugmentBox::new_with_size(Point::new(1610, 1550), 1210, 1210),
+    ]
 }
 
 impl Default for CleanAugmentConfig {
     fn default() -> Self {
-        let hpsa = 15.0_f32.to_radians();
-        let m = -f32::ln(2.0) / hpsa.cos().ln();
+        let m = lambertian_order(15.0_f32.to_radians());
         CleanAugmentConfig {
+            unit: Unit::Mm,
+            input_unit: Unit::Mm,
+            grid_resolution: 1,
+            snap: SnapPolicy::Nearest,
             clean_dist: 30,
             augm_dist: 50,
-            continuity_thresh: 0.08,
+            continuity_thresholds: vec![0.08; 36],
+            threshold_mode: ThresholdMode::Absolute,
             led_count: 36,
             height: 176 * 10,
-            led_positions: (0..36).map(led_to_point).collect(),
-            half_power_semiangle: hpsa,
-            lambertian_order: m,
-            prop_loss_func: |d| d.powi(-2),
+            led_positions: (0..36).map(led_to_position).collect(),
+            led_heights: vec![176.0 * 10.0; 36],
+            led_normals: vec![[0.0, 0.0, -1.0]; 36],
+            receiver_height: 0.0,
+            receiver_normal: [0.0, 0.0, 1.0],
+            lambertian_orders: vec![m; 36],
+            led_powers: vec![1.0; 36],
+            calibrated_powers: false,
+            propagation: vec![PropagationModel::default(); 36],
             augm_min_neighbors: 10,
-            darkness_penalty: 3.0,
-            led_fov: 30.0_f32.to_radians(),
+            hybrid_shrinkage: 10,
+            darkness_penalties: vec![3.0; 36],
+            score_method: ScoreMethod::Mean,
+            led_fovs: vec![30.0_f32.to_radians(); 36],
+            fov_outside: FovPolicy::Zero,
+            fov_reject_sources: false,
             augm_min_neighbors2: 4,
+            augm_max_iters: 20,
+            augm_tolerance: 1e-4,
+            augm_refine: false,
+            augment_boxes: default_augment_boxes(),
+            augment_exclude: Vec::new(),
+            interpolation: InterpolationMethod::Neighbor,
+            led_health: HealthCheck::default(),
+            rooms: BTreeMap::new(),
+        }
+    }
+}
+
+/// Lambertian emission order of an LED with the given half-power semiangle (radians).
+pub fn lambertian_order(half_power_semiangle: f32) -> f32 {
+    -f32::ln(2.0) / half_power_semiangle.cos().ln()
+}
+
+/// Lambertian order of the configured half-power semiangle `name`, which must
+/// be in radians and within (0, π/2).
+fn semiangle_order(name: &str, half_power_semiangle: f32) -> Result<f32, String> {
+    if !(half_power_semiangle > 0.0 && half_power_semiangle < FRAC_PI_2) {
+        return Err(format!(
+            "{} {} must be in radians between 0 and π/2 (exclusive)",
+            name, half_power_semiangle
+        ));
+    }
+    Ok(lambertian_order(half_power_semiangle))
+}
+
+/// Checks that the configured field of view `name` is in radians and within
+/// (0, π].
+fn check_fov(name: &str, fov: f32) -> Result<f32, String> {
+    if !(fov > 0.0 && fov <= PI) {
+        return Err(format!(
+            "{} {} must be in radians between 0 (exclusive) and π",
+            name, fov
+        ));
+    }
+    Ok(fov)
+}
+
+fn normalize([x, y, z]: [f32; 3]) -> Result<[f32; 3], String> {
+    let len = (x * x + y * y + z * z).sqrt();
+    if len == 0.0 || !len.is_finite() {
+        return Err(format!("invalid normal vector [{}, {}, {}]", x, y, z));
+    }
+    Ok([x / len, y / len, z / len])
+}
+
+/// Groups of configuration keys that set the same values in different forms
+/// and cannot be combined.
+const ALTERNATIVES: &[&[&str]] = &[
+    &["continuity_thresh", "continuity_thresholds"],
+    &["darkness_penalty", "darkness_penalties"],
+    &["led_fov", "led_fovs"],
+    &["propagation", "path_loss_exponents"],
+    &[
+        "half_power_semiangle",
+        "half_power_semiangles",
+        "lambertian_order",
+        "lambertian_orders",
+    ],
+];
+
+/// Reads the configuration table at `path`, an empty table without a path.
+pub fn read_table(path: Option<&Path>) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    match path {
+        Some(path) => Ok(std::fs::read_to_string(path)?.parse::<toml::Table>()?),
+        None => Ok(toml::Table::new()),
+    }
+}
+
+/// Sets `key` to `value` in the configuration `table`, removing the keys that
+/// set the same values in another form.
+pub fn set_key(table: &mut toml::Table, key: &str, value: toml::Value) {
+    if let Some(group) = ALTERNATIVES.iter().find(|group| group.contains(&key)) {
+        for other in group.iter() {
+            table.remove(*other);
         }
     }
+    table.insert(key.to_owned(), value);
+}
+
+/// Table of `room` in the configuration `table`, created if missing, or
+/// `table` itself without a room.
+pub fn room_table<'a>(
+    table: &'a mut toml::Table,
+    room: Option<&str>,
+) -> Result<&'a mut toml::Table, String> {
+    let Some(room) = room else {
+        return Ok(table);
+    };
+    let rooms = table
+        .entry("rooms")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        .as_table_mut()
+        .ok_or("rooms must be a table of room configurations")?;
+    rooms
+        .entry(room)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format!("configuration of room {} must be a table", room))
+}
+
+fn per_led<T: Clone>(name: &str, values: &[T], led_count: usize) -> Result<Vec<T>, String> {
+    if values.len() != led_count {
+        return Err(format!(
+            "{} has {} entries, expected {}",
+            name,
+            values.len(),
+            led_count
+        ));
+    }
+    Ok(values.to_vec())
 }
 
 impl CleanAugmentConfig {
+    /// Reads the configuration at `path`. Each `[rooms.<id>]` table overrides
+    /// top-level keys for the records of that room.
     pub fn from_file(path: &Path) -> Result<CleanAugmentConfig, Box<dyn std::error::Error>> {
-        let config_file = std::fs::read_to_string(path)?;
-        let config_builder: ConfigBuilder = toml::from_str(&config_file)?;
-        Ok(config_builder.build())
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        Self::from_toml(&std::fs::read_to_string(path)?, base_dir)
+    }
+
+    /// Parses the configuration `text`, resolving paths against `base_dir`.
+    pub fn from_toml(
+        text: &str,
+        base_dir: &Path,
+    ) -> Result<CleanAugmentConfig, Box<dyn std::error::Error>> {
+        let mut table = text.parse::<toml::Table>()?;
+        let rooms = match table.remove("rooms") {
+            Some(toml::Value::Table(rooms)) => rooms,
+            Some(_) => return Err("rooms must be a table of room configurations".into()),
+            None => toml::Table::new(),
+        };
+        let mut config: CleanAugmentConfig = toml::Value::Table(table.clone())
+            .try_into::<ConfigBuilder>()?
+            .build(base_dir)?;
+        for (room, overrides) in rooms {
+            let toml::Value::Table(overrides) = overrides else {
+                return Err(format!("configuration of room {} must be a table", room).into());
+            };
+            let mut room_table = table.clone();
+            for (key, value) in overrides {
+                set_key(&mut room_table, &key, value);
+            }
+            let room_config = toml::Value::Table(room_table)
+                .try_into::<ConfigBuilder>()?
+                .build(base_dir)
+                .map_err(|e| format!("room {}: {}", room, e))?;
+            // All rooms are written to the same columns
+            if room_config.led_count != config.led_count {
+                return Err(format!(
+                    "room {} has {} LEDs, expected {}",
+                    room, room_config.led_count, config.led_count
+                )
+                .into());
+            }
+            config.rooms.insert(room, room_config);
+        }
+        Ok(config)
+    }
+
+    /// Configuration of only the LEDs `leds`, which take the indices of their
+    /// position in `leds`.
+    pub fn select_leds(&self, leds: &[usize]) -> CleanAugmentConfig {
+        fn select<T: Clone>(values: &[T], leds: &[usize]) -> Vec<T> {
+            leds.iter().map(|&i| values[i].clone()).collect()
+        }
+        CleanAugmentConfig {
+            continuity_thresholds: select(&self.continuity_thresholds, leds),
+            led_count: leds.len(),
+            led_positions: select(&self.led_positions, leds),
+            led_heights: select(&self.led_heights, leds),
+            led_normals: select(&self.led_normals, leds),
+            lambertian_orders: select(&self.lambertian_orders, leds),
+            led_powers: select(&self.led_powers, leds),
+            propagation: select(&self.propagation, leds),
+            darkness_penalties: select(&self.darkness_penalties, leds),
+            led_fovs: select(&self.led_fovs, leds),
+            rooms: BTreeMap::new(),
+            ..self.clone()
+        }
     }
+
+    /// Fails unless the LED powers were configured, as `what` predicts RSS
+    /// values with the channel model.
+    pub fn require_led_powers(&self, what: &str) -> Result<(), String> {
+        if self.calibrated_powers {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} needs calibrated led_powers, set them or run calibrate",
+                what
+            ))
+        }
+    }
+
+    /// Configuration of the records of `room`, the top-level configuration
+    /// for records without a room. Fails for a room without a configuration.
+    pub fn for_room(&self, room: Option<&str>) -> Result<&CleanAugmentConfig, String> {
+        match room {
+            None => Ok(self),
+            Some(room) => self
+                .rooms
+                .get(room)
+                .ok_or_else(|| format!("room {} has no [rooms.{}] configuration", room, room)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ScorerBuilder {
+    #[serde(flatten)]
+    method: ScoreMethod,
+    darkness_penalty: Option<f32>,
 }
 
 #[derive(Deserialize, Debug)]
 struct ConfigBuilder {
-    clean_dist: Option<u32>,
-    augm_dist: Option<u32>,
+    unit: Option<Unit>,
+    input_unit: Option<Unit>,
+    grid_resolution: Option<usize>,
+    snap: Option<SnapPolicy>,
+    clean_dist: Option<usize>,
+    augm_dist: Option<usize>,
+    /// Continuity threshold of all LEDs, in the unit described on
+    /// `CleanAugmentConfig::continuity_thresholds`
     continuity_thresh: Option<f32>,
+    /// Continuity threshold of each LED
+    continuity_thresholds: Option<Vec<f32>>,
+    threshold_mode: Option<ThresholdMode>,
     led_count: Option<usize>,
     height: Option<u32>,
-    led_positions: Option<Vec<[f32; 2]>>,
+    led_positions: Option<Vec<Vec<f32>>>,
+    led_normals: Option<Vec<[f32; 3]>>,
+    receiver_height: Option<f32>,
+    receiver_normal: Option<[f32; 3]>,
+    /// Half-power semiangle of all LEDs in radians, within (0, π/2)
     half_power_semiangle: Option<f32>,
+    /// Half-power semiangle of each LED in radians, within (0, π/2)
+    half_power_semiangles: Option<Vec<f32>>,
+    lambertian_order: Option<f32>,
+    lambertian_orders: Option<Vec<f32>>,
+    led_powers: Option<Vec<f32>>,
+    path_loss_exponents: Option<Vec<f32>>,
     augm_min_neighbors: Option<usize>,
+    hybrid_shrinkage: Option<usize>,
+    augm_max_iters: Option<usize>,
+    augm_tolerance: Option<f32>,
+    augm_refine: Option<bool>,
     darkness_penalty: Option<f32>,
+    darkness_penalties: Option<Vec<f32>>,
+    scorer: Option<ScorerBuilder>,
+    propagation: Option<PropagationBuilder>,
+    augment: Option<AugmentBuilder>,
+    /// Field of view of all LEDs in radians, within (0, π]
+    led_fov: Option<f32>,
+    /// Field of view of each LED in radians, within (0, π]
+    led_fovs: Option<Vec<f32>>,
+    fov_outside: Option<FovPolicy>,
+    fov_reject_sources: Option<bool>,
+    interpolation: Option<InterpolationMethod>,
+    led_health: Option<HealthCheck>,
 }
 
 impl ConfigBuilder {
-    fn build(self) -> CleanAugmentConfig {
+    fn build(self, base_dir: &Path) -> Result<CleanAugmentConfig, Box<dyn std::error::Error>> {
         let default = CleanAugmentConfig::default();
-        CleanAugmentConfig {
+        let led_count = self.led_count.unwrap_or(default.led_count);
+        // The scorer's own darkness penalty takes precedence over the global one
+        let darkness_penalty = self
+            .scorer
+            .as_ref()
+            .and_then(|s| s.darkness_penalty)
+            .or(self.darkness_penalty);
+        let darkness_penalties = match (&self.darkness_penalties, darkness_penalty) {
+            (Some(_), Some(_)) => {
+                return Err("darkness_penalties cannot be combined with darkness_penalty".into())
+            }
+            (Some(penalties), None) => per_led("darkness_penalties", penalties, led_count)?,
+            (None, Some(penalty)) => vec![penalty; led_count],
+            (None, None) => vec![default.darkness_penalties[0]; led_count],
+        };
+        let continuity_thresholds = match (&self.continuity_thresholds, self.continuity_thresh) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "continuity_thresholds cannot be combined with continuity_thresh".into(),
+                )
+            }
+            (Some(thresholds), None) => per_led("continuity_thresholds", thresholds, led_count)?,
+            (None, Some(threshold)) => vec![threshold; led_count],
+            (None, None) => vec![default.continuity_thresholds[0]; led_count],
+        };
+        let height = self.height.unwrap_or(default.height);
+        let (led_positions, led_heights): (Vec<_>, Vec<_>) = match &self.led_positions {
+            Some(positions) => per_led("led_positions", positions, led_count)?
+                .iter()
+                .map(|p| match p[..] {
+                    [x, y] => Ok((Position::new(x, y), height as f32)),
+                    [x, y, z] => Ok((Position::new(x, y), z)),
+                    _ => Err(format!("LED position {:?} must be [x, y] or [x, y, z]", p)),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .unzip(),
+            None => (
+                (0..led_count).map(led_to_position).collect(),
+                vec![height as f32; led_count],
+            ),
+        };
+        let led_normals = match &self.led_normals {
+            Some(normals) => per_led("led_normals", normals, led_count)?
+                .into_iter()
+                .map(normalize)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![[0.0, 0.0, -1.0]; led_count],
+        };
+        let receiver_normal = match self.receiver_normal {
+            Some(n) => normalize(n)?,
+            None => default.receiver_normal,
+        };
+        let lambertian_orders = self.lambertian_orders(led_count, &default)?;
+        let led_fovs = match (&self.led_fovs, self.led_fov) {
+            (Some(_), Some(_)) => return Err("led_fovs cannot be combined with led_fov".into()),
+            (Some(fovs), None) => per_led("led_fovs", fovs, led_count)?
+                .into_iter()
+                .map(|fov| check_fov("led_fovs entry", fov))
+                .collect::<Result<_, _>>()?,
+            (None, Some(fov)) => vec![check_fov("led_fov", fov)?; led_count],
+            (None, None) => vec![default.led_fovs[0]; led_count],
+        };
+        let led_powers = match &self.led_powers {
+            Some(powers) => per_led("led_powers", powers, led_count)?,
+            None => vec![default.led_powers[0]; led_count],
+        };
+        let propagation = match (self.propagation, &self.path_loss_exponents) {
+            (Some(_), Some(_)) => {
+                return Err("path_loss_exponents cannot be combined with propagation".into())
+            }
+            (Some(builder), None) => vec![builder.build(base_dir)?; led_count],
+            (None, Some(exponents)) => per_led("path_loss_exponents", exponents, led_count)?
+                .into_iter()
+                .map(|exponent| PropagationModel::InversePower { exponent })
+                .collect(),
+            (None, None) => vec![default.propagation[0].clone(); led_count],
+        };
+        let (augment_boxes, augment_exclude) = match self.augment {
+            Some(builder) => builder.build()?,
+            None => (default.augment_boxes, default.augment_exclude),
+        };
+        let threshold_mode = self.threshold_mode.unwrap_or(default.threshold_mode);
+        if threshold_mode == ThresholdMode::Model && self.led_powers.is_none() {
+            return Err("threshold_mode = \"model\" needs calibrated led_powers".into());
+        }
+        let unit = self.unit.unwrap_or(default.unit);
+        let grid_resolution = self.grid_resolution.unwrap_or(default.grid_resolution);
+        if grid_resolution == 0 {
+            return Err("grid_resolution must be positive".into());
+        }
+        // Augmented points must be grid points, which are all the map can hold
+        if let Some(b) = augment_boxes
+            .iter()
+            .find(|b| b.resolution() % grid_resolution != 0)
+        {
+            return Err(format!(
+                "augment resolution {} is not a multiple of grid_resolution {}",
+                b.resolution(),
+                grid_resolution
+            )
+            .into());
+        }
+        Ok(CleanAugmentConfig {
+            unit,
+            input_unit: self.input_unit.unwrap_or(unit),
+            grid_resolution,
+            snap: self.snap.unwrap_or(default.snap),
             clean_dist: self.clean_dist.unwrap_or(default.clean_dist),
             augm_dist: self.augm_dist.unwrap_or(default.augm_dist),
-            continuity_thresh: self.continuity_thresh.unwrap_or(default.continuity_thresh),
-            led_count: self.led_count.unwrap_or(default.led_count),
-            height: self.height.unwrap_or(default.height),
-            led_positions: self
-                .led_positions
-                .map(|v| {
-                    v.into_iter()
-                        .map(|[x, y]| Point {
-                            x: x as u32,
-                            y: y as u32,
-                        })
-                        .collect()
-                })
-                .unwrap_or(default.led_positions),
-            half_power_semiangle: self
-                .half_power_semiangle
-                .unwrap_or(default.half_power_semiangle),
-            lambertian_order: default.lambertian_order,
-            prop_loss_func: default.prop_loss_func,
+            continuity_thresholds,
+            threshold_mode,
+            led_count,
+            height,
+            led_positions,
+            led_heights,
+            led_normals,
+            receiver_height: self.receiver_height.unwrap_or(default.receiver_height),
+            receiver_normal,
+            lambertian_orders,
+            led_powers,
+            calibrated_powers: self.led_powers.is_some(),
+            propagation,
             augm_min_neighbors: self
                 .augm_min_neighbors
                 .unwrap_or(default.augm_min_neighbors),
-            darkness_penalty: self.darkness_penalty.unwrap_or(default.darkness_penalty),
-            led_fov: default.led_fov,
+            hybrid_shrinkage: self.hybrid_shrinkage.unwrap_or(default.hybrid_shrinkage),
+            darkness_penalties,
+            score_method: self
+                .scorer
+                .as_ref()
+                .map_or(default.score_method, |s| s.method),
+            led_fovs,
+            fov_outside: self.fov_outside.unwrap_or(default.fov_outside),
+            fov_reject_sources: self
+                .fov_reject_sources
+                .unwrap_or(default.fov_reject_sources),
             augm_min_neighbors2: default.augm_min_neighbors2,
+            augm_max_iters: self.augm_max_iters.unwrap_or(default.augm_max_iters),
+            augm_tolerance: self.augm_tolerance.unwrap_or(default.augm_tolerance),
+            augm_refine: self.augm_refine.unwrap_or(default.augm_refine),
+            augment_boxes,
+            augment_exclude,
+            interpolation: self.interpolation.unwrap_or(default.interpolation),
+            led_health: self.led_health.unwrap_or(default.led_health),
+            rooms: BTreeMap::new(),
+        })
+    }
+
+    fn lambertian_orders(
+        &self,
+        led_count: usize,
+        default: &CleanAugmentConfig,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let per_led_given =
+            self.half_power_semiangles.is_some() || self.lambertian_orders.is_some();
+        let global_given = self.half_power_semiangle.is_some() || self.lambertian_order.is_some();
+        if per_led_given && global_given {
+            return Err(
+                "per-LED half_power_semiangles/lambertian_orders cannot be combined \
+                        with a global half_power_semiangle or lambertian_order"
+                    .into(),
+            );
         }
+        match (&self.half_power_semiangles, &self.lambertian_orders) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "half_power_semiangles cannot be combined with lambertian_orders".into(),
+                )
+            }
+            (Some(semiangles), None) => {
+                return Ok(per_led("half_power_semiangles", semiangles, led_count)?
+                    .into_iter()
+                    .map(|hpsa| semiangle_order("half_power_semiangles entry", hpsa))
+                    .collect::<Result<_, _>>()?)
+            }
+            (None, Some(orders)) => return Ok(per_led("lambertian_orders", orders, led_count)?),
+            (None, None) => {}
+        }
+        let order = match (self.half_power_semiangle, self.lambertian_order) {
+            (Some(hpsa), Some(m)) => {
+                let derived = semiangle_order("half_power_semiangle", hpsa)?;
+                if (derived - m).abs() > 1e-3 * m.abs().max(1.0) {
+                    return Err(format!(
+                        "lambertian_order {} conflicts with half_power_semiangle {} (order {})",
+                        m, hpsa, derived
+                    )
+                    .into());
+                }
+                m
+            }
+            (Some(hpsa), None) => semiangle_order("half_power_semiangle", hpsa)?,
+            (None, Some(m)) => m,
+            (None, None) => default.lambertian_orders[0],
+        };
+        Ok(vec![order; led_count])
     }
 }
 
@@ -122,3 +617,95 @@ pub fn pb_style2() -> indicatif::ProgressStyle {
         .unwrap()
         .progress_chars("#>-")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, Box<dyn std::error::Error>> {
+        Config::from_toml(text, Path::new("."))
+    }
+
+    #[test]
+    fn model_thresholds_need_calibrated_powers() {
+        assert!(parse("threshold_mode = \"model\"").is_err());
+        let config =
+            parse("threshold_mode = \"model\"\nled_count = 1\nled_powers = [2.0]").unwrap();
+        assert_eq!(config.threshold_mode, ThresholdMode::Model);
+    }
+
+    #[test]
+    fn half_power_semiangles_are_radians_below_a_right_angle() {
+        let config = parse("half_power_semiangle = 0.5").unwrap();
+        assert!((config.lambertian_orders[0] - lambertian_order(0.5)).abs() < 1e-6);
+        // Degrees, negative and right angles are rejected
+        assert!(parse("half_power_semiangle = 15.0").is_err());
+        assert!(parse("half_power_semiangle = -0.5").is_err());
+        assert!(parse("half_power_semiangle = 1.5707964").is_err());
+        assert!(parse("led_count = 2\nhalf_power_semiangles = [0.5, 60.0]").is_err());
+    }
+
+    #[test]
+    fn fields_of_view_are_radians_up_to_pi() {
+        assert_eq!(parse("led_fov = 3.0").unwrap().led_fovs[0], 3.0);
+        assert!(parse("led_fov = 60.0").is_err());
+        assert!(parse("led_fov = 0.0").is_err());
+        assert!(parse("led_count = 2\nled_fovs = [0.5, 90.0]").is_err());
+    }
+
+    #[test]
+    fn led_positions_keep_their_decimals() {
+        let config = parse("led_count = 1\nled_positions = [[1234.5, 987.25]]").unwrap();
+        assert_eq!(config.led_positions, [Position::new(1234.5, 987.25)]);
+    }
+
+    #[test]
+    fn led_poses_are_given_for_every_led() {
+        let config = parse("led_count = 2").unwrap();
+        assert_eq!(config.led_positions, Config::default().led_positions[..2]);
+        assert!(parse("led_positions = [[250, 250], [750, 250]]").is_err());
+        assert!(parse("led_count = 2\nled_positions = [[250, 250, 1500]]").is_err());
+        assert!(parse("led_count = 1\nled_normals = [[0, 0, -1], [0, 1, -1]]").is_err());
+        let config = parse(
+            "led_count = 2\nled_positions = [[250, 250], [750, 250, 1500]]\n\
+             led_normals = [[0, 0, -1], [0, 1, -1]]",
+        )
+        .unwrap();
+        assert_eq!(config.led_heights[1], 1500.0);
+        assert_eq!(config.led_normals.len(), 2);
+    }
+
+    #[test]
+    fn rooms_must_be_configured() {
+        let config = parse("[rooms.a]\nclean_dist = 5").unwrap();
+        assert_eq!(config.for_room(Some("a")).unwrap().clean_dist, 5);
+        assert_eq!(config.for_room(None).unwrap().clean_dist, config.clean_dist);
+        assert!(config.for_room(Some("b")).is_err());
+    }
+
+    #[test]
+    fn room_keys_replace_their_top_level_alternatives() {
+        let config = parse(
+            "led_count = 2\nlambertian_order = 2.0\n[rooms.a]\nlambertian_orders = [1.0, 3.0]",
+        )
+        .unwrap();
+        assert_eq!(config.lambertian_orders, vec![2.0, 2.0]);
+        assert_eq!(
+            config.for_room(Some("a")).unwrap().lambertian_orders,
+            vec![1.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn augment_resolution_must_be_a_multiple_of_the_grid() {
+        let text = |resolution| {
+            format!(
+                "grid_resolution = 4\n[augment]\nresolution = {}\n\
+                 boxes = [{{ ll = [0, 0], ur = [100, 100] }}]",
+                resolution
+            )
+        };
+        assert!(parse(&text(8)).is_ok());
+        assert!(parse(&text(10)).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    build_radio_map,
+    config::Config,
+    locate::{fingerprints, Knn},
+    open_output,
+    point::{Point, Position},
+    point_map::{PointMap, PointMapConfig},
+    rss_record::{read_records, RssRecord},
+    PipelineArgs,
+};
+
+/// How measured points are split into training and test points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SplitMethod {
+    /// Random points of each room
+    Random,
+    /// Random square blocks of points, so that test points are not
+    /// surrounded by training points
+    Block,
+    /// Every point is tested once, in one of several folds
+    KFold,
+}
+
+/// Measured point of a room.
+pub type Key = (Option<String>, Point);
+
+#[derive(Debug, Clone)]
+pub struct Split {
+    pub method: SplitMethod,
+    pub test_fraction: f32,
+    pub folds: usize,
+    pub block_size: usize,
+    pub seed: u64,
+}
+
+impl Split {
+    /// Test points of each evaluation round.
+    pub fn test_sets(&self, keys: &[Key]) -> Vec<HashSet<Key>> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let test_count = |n: usize| {
+            ((n as f32 * self.test_fraction).round() as usize).clamp(1, n.saturating_sub(1).max(1))
+        };
+        match self.method {
+            SplitMethod::Random => {
+                let mut rooms = BTreeMap::<_, Vec<_>>::new();
+                for (room, p) in keys {
+                    rooms.entry(room).or_default().push(*p);
+                }
+                let mut test = HashSet::new();
+                for (room, points) in rooms {
+                    let mut measured = PointMap::with_occupancy(
+                        PointMapConfig::bounding(&points, 1),
+                        points.len(),
+                    );
+                    for &p in &points {
+                        measured.insert(p, Some(()));
+                    }
+                    let sample = measured.subsample(
+                        || points[rng.random_range(0..points.len())],
+                        test_count(points.len()),
+                    );
+                    test.extend(
+                        points
+                            .iter()
+                            .filter(|&&p| sample.get(p).is_some_and(Option::is_some))
+                            .map(|&p| (room.clone(), p)),
+                    );
+                }
+                vec![test]
+            }
+            SplitMethod::Block => {
+                let mut blocks = BTreeMap::<_, Vec<_>>::new();
+                for key in keys {
+                    let block = key.1.with_resolution(self.block_size);
+                    blocks.entry((&key.0, block)).or_default().push(key.clone());
+                }
+                let mut blocks = blocks.into_values().collect::<Vec<_>>();
+                blocks.shuffle(&mut rng);
+                let target = test_count(keys.len());
+                let mut test = HashSet::new();
+                for block in blocks {
+                    if test.len() >= target {
+                        break;
+                    }
+                    test.extend(block);
+                }
+                vec![test]
+            }
+            SplitMethod::KFold => {
+                let mut keys = keys.to_vec();
+                keys.shuffle(&mut rng);
+                let folds = self.folds.clamp(2, keys.len().max(2));
+                (0..folds)
+                    .map(|fold| keys.iter().skip(fold).step_by(folds).cloned().collect())
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Positioning errors of the test points of all rounds.
+#[derive(Debug, Clone, Default)]
+struct Evaluation {
+    /// Errors of the located test records, sorted
+    errors: Vec<f32>,
+    /// Test records that could not be located
+    failed: usize,
+    /// Located test records assigned to their own room
+    room_hits: usize,
+    /// Whether the records have room ids, without which there is no room
+    /// accuracy
+    rooms: bool,
+}
+
+impl Evaluation {
+    fn percentile(&self, p: f32) -> Option<f32> {
+        if self.errors.is_empty() {
+            return None;
+        }
+        Some(self.errors[(p * (self.errors.len() - 1) as f32).round() as usize])
+    }
+
+    fn mean(&self) -> Option<f32> {
+        (!self.errors.is_empty())
+            .then(|| self.errors.iter().sum::<f32>() / self.errors.len() as f32)
+    }
+
+    /// Fields of the summary line. The statistics of the errors are empty if
+    /// no test record was located, the room accuracy also without rooms.
+    fn summary(&self) -> [String; 6] {
+        let located = self.errors.len();
+        let field = |v: Option<f32>| v.map_or_else(String::new, |v| v.to_string());
+        [
+            (located + self.failed).to_string(),
+            located.to_string(),
+            field(self.mean()),
+            field(self.percentile(0.5)),
+            field(self.percentile(0.9)),
+            field((self.rooms && located > 0).then(|| self.room_hits as f32 / located as f32)),
+        ]
+    }
+}
+
+/// Locates the `test` records with the radio map built from `train`.
+fn evaluate_round(
+    train: Vec<RssRecord>,
+    test: &[RssRecord],
+    knn: &Knn,
+    config: &Config,
+    pipeline: &PipelineArgs,
+    evaluation: &mut Evaluation,
+) -> Result<(), Box<dyn Error>> {
+    let (radio_map, _) = build_radio_map(train, config, pipeline)?;
+    let fingerprints = fingerprints(radio_map);
+    for record in test {
+        let Some(estimate) = knn.locate(&record.rss, &fingerprints) else {
+            evaluation.failed += 1;
+            continue;
+        };
+        if estimate.room == record.room {
+            evaluation.room_hits += 1;
+        }
+        let truth = Position::from(&record.point);
+        evaluation.errors.push(estimate.position.dist(&truth));
+    }
+    Ok(())
+}
+
+pub fn run(
+    input: &Path,
+    output: Option<&Path>,
+    split: &Split,
+    knn: &Knn,
+    config: &Config,
+    pipeline: &PipelineArgs,
+) -> Result<(), Box<dyn Error>> {
+    let records = read_records(input, config)?;
+    let mut keys = records
+        .iter()
+        .map(|r| (r.room.clone(), r.point))
+        .collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys.dedup();
+    if keys.len() < 2 {
+        return Err("at least two measured points are needed for an evaluation".into());
+    }
+
+    let mut evaluation = Evaluation {
+        rooms: keys.iter().any(|(room, _)| room.is_some()),
+        ..Evaluation::default()
+    };
+    for test_set in split.test_sets(&keys) {
+        let (test, train): (Vec<_>, Vec<_>) = records
+            .iter()
+            .cloned()
+            .partition(|r| test_set.contains(&(r.room.clone(), r.point)));
+        evaluate_round(train, &test, knn, config, pipeline, &mut evaluation)?;
+    }
+    evaluation.errors.sort_by(f32::total_cmp);
+
+    let located = evaluation.errors.len();
+    if located == 0 {
+        eprintln!("None of the test records could be located");
+    }
+    eprintln!("queries,located,mean,median,p90,room_accuracy");
+    eprintln!("{}", evaluation.summary().join(","));
+
+    let mut wtr = csv::Writer::from_writer(open_output(output)?);
+    wtr.write_record(["error", "cdf"])?;
+    for (i, error) in evaluation.errors.iter().enumerate() {
+        wtr.write_record([
+            error.to_string(),
+            ((i + 1) as f32 / located as f32).to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_split_takes_a_fraction_of_each_room() {
+        let keys = ["a", "b"]
+            .into_iter()
+            .flat_map(|room| (0..10).map(move |x| (Some(room.to_owned()), Point::new(x, 0))))
+            .collect::<Vec<_>>();
+        let split = Split {
+            method: SplitMethod::Random,
+            test_fraction: 0.3,
+            folds: 5,
+            block_size: 1,
+            seed: 1,
+        };
+        let sets = split.test_sets(&keys);
+        assert_eq!(sets.len(), 1);
+        for room in ["a", "b"] {
+            let count = sets[0]
+                .iter()
+                .filter(|(r, _)| r.as_deref() == Some(room))
+                .count();
+            assert_eq!(count, 3);
+        }
+        assert_eq!(split.test_sets(&keys), sets);
+    }
+
+    #[test]
+    fn nothing_located_is_reported_without_statistics() {
+        let evaluation = Evaluation {
+            failed: 4,
+            ..Evaluation::default()
+        };
+        assert_eq!(evaluation.summary().join(","), "4,0,,,,");
+        let evaluation = Evaluation {
+            errors: vec![1.0, 3.0],
+            failed: 0,
+            room_hits: 1,
+            rooms: true,
+        };
+        assert_eq!(evaluation.summary().join(","), "2,2,2,3,3,0.5");
+        // Records without rooms all match the estimated room `None`
+        let evaluation = Evaluation {
+            room_hits: 2,
+            rooms: false,
+            ..evaluation
+        };
+        assert_eq!(evaluation.summary().join(","), "2,2,2,3,3,");
+    }
+}
@@ -0,0 +1,126 @@
+/// Result of a nonlinear least-squares fit.
+#[derive(Debug, Clone)]
+pub struct Fit {
+    pub params: Vec<f64>,
+    pub residuals: Vec<f64>,
+    pub iterations: usize,
+}
+
+impl Fit {
+    pub fn rmse(&self) -> f64 {
+        (sum_sq(&self.residuals) / self.residuals.len().max(1) as f64).sqrt()
+    }
+}
+
+fn sum_sq(r: &[f64]) -> f64 {
+    r.iter().map(|r| r * r).sum()
+}
+
+fn jacobian(f: &impl Fn(&[f64]) -> Vec<f64>, params: &[f64], r0: &[f64]) -> Vec<Vec<f64>> {
+    let mut p = params.to_vec();
+    (0..params.len())
+        .map(|j| {
+            let h = 1e-6 * params[j].abs().max(1e-3);
+            p[j] = params[j] + h;
+            let r = f(&p);
+            p[j] = params[j];
+            r.iter().zip(r0).map(|(r, r0)| (r - r0) / h).collect()
+        })
+        .collect()
+}
+
+/// Solves `a * x = b` by Gaussian elimination with partial pivoting.
+pub fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-300 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let (upper, lower) = a.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for (row, b_row) in lower.iter_mut().zip(col + 1..n) {
+            let factor = row[col] / pivot_row[col];
+            for (v, p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *v -= factor * p;
+            }
+            b[b_row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - s) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Minimizes the sum of squared `residuals` with the Levenberg-Marquardt
+/// method, using a forward-difference Jacobian.
+pub fn levenberg_marquardt(
+    residuals: impl Fn(&[f64]) -> Vec<f64>,
+    initial: Vec<f64>,
+    max_iters: usize,
+) -> Fit {
+    let mut params = initial;
+    let mut r = residuals(&params);
+    let mut cost = sum_sq(&r);
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+
+    while iterations < max_iters {
+        iterations += 1;
+        let jac = jacobian(&residuals, &params, &r);
+        let n = params.len();
+        let jtj = (0..n)
+            .map(|i| {
+                (0..n)
+                    .map(|k| jac[i].iter().zip(&jac[k]).map(|(a, b)| a * b).sum())
+                    .collect::<Vec<f64>>()
+            })
+            .collect::<Vec<_>>();
+        let jtr = (0..n)
+            .map(|i| -jac[i].iter().zip(&r).map(|(a, b)| a * b).sum::<f64>())
+            .collect::<Vec<_>>();
+
+        let mut improved = false;
+        while lambda < 1e12 {
+            let mut damped = jtj.clone();
+            for (i, row) in damped.iter_mut().enumerate() {
+                row[i] += lambda * jtj[i][i].max(1e-12);
+            }
+            let Some(step) = solve(damped, jtr.clone()) else {
+                lambda *= 10.0;
+                continue;
+            };
+            let candidate = params
+                .iter()
+                .zip(&step)
+                .map(|(p, s)| p + s)
+                .collect::<Vec<_>>();
+            let r_new = residuals(&candidate);
+            let cost_new = sum_sq(&r_new);
+            if cost_new.is_finite() && cost_new < cost {
+                let converged = (cost - cost_new) <= 1e-10 * cost;
+                params = candidate;
+                r = r_new;
+                cost = cost_new;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+
+    Fit {
+        params,
+        residuals: r,
+        iterations,
+    }
+}
@@ -0,0 +1,571 @@
+use serde::Deserialize;
+
+use crate::{
+    augment::{
+        current_rss, in_fov, weighted_estimate, AugmentStrategy, Augmenter, ChannelModel,
+        LambertianRatio,
+    },
+    config::Config,
+    fit::{levenberg_marquardt, solve},
+    point::Point,
+    point_map::RssMap,
+    rss_record::{RssArr, Uncertainty},
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RbfKernel {
+    #[default]
+    Gaussian,
+    Multiquadric,
+    InverseMultiquadric,
+    ThinPlate,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VariogramModel {
+    #[default]
+    Spherical,
+    Exponential,
+    Gaussian,
+}
+
+fn default_idw_power() -> f32 {
+    2.0
+}
+
+fn default_neighbors() -> usize {
+    16
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", rename_all = "snake_case", deny_unknown_fields)]
+pub enum InterpolationMethod {
+    Neighbor,
+    Model,
+    Hybrid,
+    Idw {
+        #[serde(default = "default_idw_power")]
+        power: f32,
+    },
+    Rbf {
+        #[serde(default)]
+        kernel: RbfKernel,
+        epsilon: Option<f32>,
+        #[serde(default = "default_neighbors")]
+        neighbors: usize,
+    },
+    Kriging {
+        #[serde(default)]
+        variogram: VariogramModel,
+        #[serde(default = "default_neighbors")]
+        neighbors: usize,
+    },
+}
+
+impl From<AugmentStrategy> for InterpolationMethod {
+    fn from(strategy: AugmentStrategy) -> Self {
+        match strategy {
+            AugmentStrategy::Neighbor => InterpolationMethod::Neighbor,
+            AugmentStrategy::Model => InterpolationMethod::Model,
+            AugmentStrategy::Hybrid => InterpolationMethod::Hybrid,
+        }
+    }
+}
+
+/// Creates the augmenter for `method`, fitting any data-dependent
+/// parameters to the values already present in `point_map`. The model-based
+/// methods fail without calibrated LED powers.
+pub fn build_augmenter(
+    method: &InterpolationMethod,
+    point_map: &RssMap,
+    config: &Config,
+) -> Result<Box<dyn Augmenter + Sync>, String> {
+    let min_pts = config.augm_min_neighbors2;
+    Ok(match *method {
+        InterpolationMethod::Neighbor => Box::new(LambertianRatio { min_pts }),
+        InterpolationMethod::Model => {
+            config.require_led_powers("model augmentation")?;
+            Box::new(ChannelModel {
+                residual_correction: false,
+            })
+        }
+        InterpolationMethod::Hybrid => {
+            config.require_led_powers("hybrid augmentation")?;
+            Box::new(ChannelModel {
+                residual_correction: true,
+            })
+        }
+        InterpolationMethod::Idw { power } => Box::new(Idw { power, min_pts }),
+        InterpolationMethod::Rbf {
+            kernel,
+            epsilon,
+            neighbors,
+        } => Box::new(Rbf {
+            kernel,
+            epsilon: epsilon.unwrap_or(1.0 / config.augm_dist as f32) as f64,
+            neighbors,
+            min_pts,
+        }),
+        InterpolationMethod::Kriging {
+            variogram,
+            neighbors,
+        } => Box::new(Kriging::fit(
+            variogram, neighbors, min_pts, point_map, config,
+        )),
+    })
+}
+
+fn dist(a: &Point, b: &Point) -> f64 {
+    (a.dist_sq(b) as f64).sqrt()
+}
+
+/// Finite values of LED `led_idx` among `neighbors`, nearest first. With
+/// `fov_reject_sources`, values outside the LED's field of view are left out.
+fn led_samples(
+    point: &Point,
+    neighbors: &[(Point, &RssArr)],
+    led_idx: usize,
+    max_count: usize,
+    config: &Config,
+) -> Vec<(Point, f64)> {
+    let mut samples = neighbors
+        .iter()
+        .filter(|(p, rss)| {
+            rss[led_idx].is_finite() && (!config.fov_reject_sources || in_fov(p, led_idx, config))
+        })
+        .map(|(p, rss)| (*p, rss[led_idx] as f64))
+        .collect::<Vec<_>>();
+    samples.sort_by_key(|(p, _)| p.dist_sq(point));
+    samples.truncate(max_count);
+    samples
+}
+
+/// Solves the interpolation system `[K 1; 1' 0] [w; mu] = [rhs; 1]`, where
+/// `K` is given by `kernel` between the samples.
+fn solve_with_constant(
+    samples: &[(Point, f64)],
+    kernel: impl Fn(f64) -> f64,
+    rhs: Vec<f64>,
+) -> Option<Vec<f64>> {
+    let n = samples.len();
+    let mut a = vec![vec![1.0; n + 1]; n + 1];
+    for (i, (p, _)) in samples.iter().enumerate() {
+        for (j, (q, _)) in samples.iter().enumerate() {
+            a[i][j] = kernel(dist(p, q));
+        }
+    }
+    a[n][n] = 0.0;
+    solve(a, rhs)
+}
+
+/// Inverse distance weighting.
+pub struct Idw {
+    power: f32,
+    min_pts: usize,
+}
+
+impl Augmenter for Idw {
+    fn augment_led(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f32, Uncertainty)> {
+        let samples = led_samples(point, neighbors, led_idx, usize::MAX, config);
+        if samples.len() < self.min_pts {
+            return None;
+        }
+        Some(weighted_estimate(samples.iter().map(|(p, v)| {
+            let w = dist(p, point).max(1e-6).powf(-self.power as f64);
+            (*v as f32, w as f32)
+        })))
+    }
+}
+
+/// Radial basis function interpolation over the nearest neighbors, with a
+/// constant term.
+pub struct Rbf {
+    kernel: RbfKernel,
+    epsilon: f64,
+    neighbors: usize,
+    min_pts: usize,
+}
+
+impl Rbf {
+    fn phi(&self, r: f64) -> f64 {
+        let er = self.epsilon * r;
+        match self.kernel {
+            RbfKernel::Gaussian => (-er * er).exp(),
+            RbfKernel::Multiquadric => (1.0 + er * er).sqrt(),
+            RbfKernel::InverseMultiquadric => 1.0 / (1.0 + er * er).sqrt(),
+            RbfKernel::ThinPlate if r > 0.0 => r * r * r.ln(),
+            RbfKernel::ThinPlate => 0.0,
+        }
+    }
+}
+
+impl Rbf {
+    /// Value at `point` of the interpolant through `samples`.
+    fn interpolate(&self, point: &Point, samples: &[(Point, f64)]) -> Option<f64> {
+        let rhs = samples.iter().map(|(_, v)| *v).chain([0.0]).collect();
+        let weights = solve_with_constant(samples, |r| self.phi(r), rhs)?;
+        let value = samples
+            .iter()
+            .zip(&weights)
+            .map(|((p, _), w)| w * self.phi(dist(p, point)))
+            .sum::<f64>()
+            + weights[samples.len()];
+        Some(value)
+    }
+}
+
+impl Augmenter for Rbf {
+    /// The uncertainty is the root mean square leave-one-out error of the
+    /// interpolant at its samples.
+    fn augment_led(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f32, Uncertainty)> {
+        let samples = led_samples(point, neighbors, led_idx, self.neighbors, config);
+        if samples.len() < self.min_pts {
+            return None;
+        }
+        let value = self.interpolate(point, &samples)?;
+        let errors = (0..samples.len())
+            .filter_map(|k| {
+                let mut others = samples.clone();
+                let (p, v) = others.remove(k);
+                self.interpolate(&p, &others).map(|e| e - v)
+            })
+            .collect::<Vec<_>>();
+        let std = if errors.is_empty() {
+            f32::NAN
+        } else {
+            (errors.iter().map(|e| e * e).sum::<f64>() / errors.len() as f64).sqrt() as f32
+        };
+        let uncertainty = Uncertainty {
+            std,
+            n: samples.len(),
+        };
+        Some((value as f32, uncertainty))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Variogram {
+    model: VariogramModel,
+    nugget: f64,
+    partial_sill: f64,
+    range: f64,
+}
+
+impl Variogram {
+    fn gamma(&self, h: f64) -> f64 {
+        if h <= 0.0 {
+            return 0.0;
+        }
+        let r = h / self.range;
+        let shape = match self.model {
+            VariogramModel::Spherical if r >= 1.0 => 1.0,
+            VariogramModel::Spherical => 1.5 * r - 0.5 * r * r * r,
+            VariogramModel::Exponential => 1.0 - (-3.0 * r).exp(),
+            VariogramModel::Gaussian => 1.0 - (-3.0 * r * r).exp(),
+        };
+        self.nugget + self.partial_sill * shape
+    }
+}
+
+const VARIOGRAM_BINS: usize = 10;
+const VARIOGRAM_MAX_POINTS: usize = 2000;
+
+/// Ordinary kriging with a variogram fitted per LED.
+pub struct Kriging {
+    variograms: Vec<Variogram>,
+    neighbors: usize,
+    min_pts: usize,
+}
+
+impl Kriging {
+    pub fn fit(
+        model: VariogramModel,
+        neighbors: usize,
+        min_pts: usize,
+        point_map: &RssMap,
+        config: &Config,
+    ) -> Self {
+        let max_lag = 2.0 * config.augm_dist as f64;
+        let bin_width = max_lag / VARIOGRAM_BINS as f64;
+        // Per LED and lag bin: sum of squared differences and pair count
+        let mut bins = vec![vec![(0.0, 0_usize); VARIOGRAM_BINS]; config.led_count];
+
+        let points = point_map.points();
+        let stride = (points.len() / VARIOGRAM_MAX_POINTS).max(1);
+        for p in points.iter().step_by(stride) {
+            let Some(rss) = current_rss(p, point_map) else {
+                continue;
+            };
+            for (q, rss_q) in point_map.in_range(p, 2 * config.augm_dist) {
+                let h = dist(p, &q);
+                if q <= *p || h >= max_lag {
+                    continue;
+                }
+                let bin = (h / bin_width) as usize;
+                for i in 0..config.led_count {
+                    if rss[i].is_finite() && rss_q[i].is_finite() {
+                        let diff = (rss[i] - rss_q[i]) as f64;
+                        bins[i][bin].0 += diff * diff;
+                        bins[i][bin].1 += 1;
+                    }
+                }
+            }
+        }
+
+        let variograms = bins
+            .iter()
+            .map(|bins| fit_variogram(model, bins, bin_width, max_lag))
+            .collect();
+        Kriging {
+            variograms,
+            neighbors,
+            min_pts,
+        }
+    }
+
+    /// Kriging estimate, its variance and the number of samples used.
+    pub fn predict(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f64, f64, usize)> {
+        let samples = led_samples(point, neighbors, led_idx, self.neighbors, config);
+        if samples.len() < self.min_pts {
+            return None;
+        }
+        let variogram = &self.variograms[led_idx];
+        let gamma_0 = samples
+            .iter()
+            .map(|(p, _)| variogram.gamma(dist(p, point)))
+            .collect::<Vec<_>>();
+        let rhs = gamma_0.iter().copied().chain([1.0]).collect();
+        let weights = solve_with_constant(&samples, |h| variogram.gamma(h), rhs)?;
+        let n = samples.len();
+        let estimate = samples.iter().zip(&weights).map(|((_, v), w)| w * v).sum();
+        let variance = gamma_0
+            .iter()
+            .zip(&weights)
+            .map(|(g, w)| w * g)
+            .sum::<f64>()
+            + weights[n];
+        Some((estimate, variance.max(0.0), n))
+    }
+}
+
+fn fit_variogram(
+    model: VariogramModel,
+    bins: &[(f64, usize)],
+    bin_width: f64,
+    max_lag: f64,
+) -> Variogram {
+    let empirical = bins
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, count))| *count > 0)
+        .map(|(b, (sum, count))| {
+            let lag = (b as f64 + 0.5) * bin_width;
+            (lag, 0.5 * sum / *count as f64, (*count as f64).sqrt())
+        })
+        .collect::<Vec<_>>();
+    let sill = empirical.iter().map(|(_, g, _)| *g).fold(0.0, f64::max);
+    if empirical.len() < 3 || sill <= 0.0 {
+        return Variogram {
+            model,
+            nugget: 0.0,
+            partial_sill: sill.max(1.0),
+            range: max_lag,
+        };
+    }
+
+    let variogram = |params: &[f64]| Variogram {
+        model,
+        nugget: params[0].abs(),
+        partial_sill: params[1].abs(),
+        range: params[2].abs().max(1e-6),
+    };
+    let residuals = |params: &[f64]| {
+        let v = variogram(params);
+        empirical
+            .iter()
+            .map(|(lag, gamma, weight)| weight * (v.gamma(*lag) - gamma))
+            .collect()
+    };
+    let fit = levenberg_marquardt(residuals, vec![0.1 * sill, 0.9 * sill, max_lag / 2.0], 100);
+    variogram(&fit.params)
+}
+
+impl Augmenter for Kriging {
+    fn augment_led(
+        &self,
+        point: &Point,
+        neighbors: &[(Point, &RssArr)],
+        led_idx: usize,
+        config: &Config,
+    ) -> Option<(f32, Uncertainty)> {
+        self.predict(point, neighbors, led_idx, config)
+            .map(|(estimate, variance, n)| {
+                let uncertainty = Uncertainty {
+                    std: variance.sqrt() as f32,
+                    n,
+                };
+                (estimate as f32, uncertainty)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{point::Position, rss_record::RssRecord};
+
+    use super::*;
+
+    #[test]
+    fn model_augmentation_needs_calibrated_powers() {
+        let map = RssMap::from_records(&[], 1);
+        let config = Config::default();
+        assert!(build_augmenter(&InterpolationMethod::Model, &map, &config).is_err());
+        assert!(build_augmenter(&InterpolationMethod::Hybrid, &map, &config).is_err());
+        assert!(build_augmenter(&InterpolationMethod::Neighbor, &map, &config).is_ok());
+
+        let config =
+            Config::from_toml("led_count = 2\nled_powers = [2.0, 3.0]", Path::new(".")).unwrap();
+        assert!(build_augmenter(&InterpolationMethod::Hybrid, &map, &config).is_ok());
+    }
+
+    #[test]
+    fn rbf_uncertainty_is_the_leave_one_out_error() {
+        let rbf = Rbf {
+            kernel: RbfKernel::Multiquadric,
+            epsilon: 0.01,
+            neighbors: 25,
+            min_pts: 3,
+        };
+        // A linear field: the values spread widely but are easy to interpolate
+        let rss = (0..25)
+            .map(|k| vec![(k / 5) as f32 * 10.0])
+            .collect::<Vec<_>>();
+        let neighbors = (0..25)
+            .map(|k| (Point::new(10 * (k / 5), 10 * (k % 5)), &rss[k as usize]))
+            .collect::<Vec<_>>();
+        let (value, uncertainty) = rbf
+            .augment_led(&Point::new(25, 25), &neighbors, 0, &Config::default())
+            .unwrap();
+        assert!((value - 25.0).abs() < 0.5, "{}", value);
+        assert_eq!(uncertainty.n, 25);
+        assert!(uncertainty.std < 1.0, "{:?}", uncertainty);
+    }
+
+    #[test]
+    fn methods_are_chosen_in_the_config() {
+        let method = |text: &str| {
+            Config::from_toml(&format!("[interpolation]\n{}", text), Path::new("."))
+                .map(|c| c.interpolation)
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            method("method = \"idw\""),
+            Ok(InterpolationMethod::Idw { power: 2.0 })
+        );
+        assert_eq!(
+            method("method = \"kriging\"\nvariogram = \"gaussian\""),
+            Ok(InterpolationMethod::Kriging {
+                variogram: VariogramModel::Gaussian,
+                neighbors: 16
+            })
+        );
+        assert!(method("method = \"idw\"\nkernel = \"gaussian\"").is_err());
+        assert!(method("method = \"spline\"").is_err());
+    }
+
+    #[test]
+    fn idw_leans_towards_the_nearest_sample() {
+        let (near, far) = (vec![1.0], vec![3.0]);
+        let neighbors = [(Point::new(10, 0), &near), (Point::new(30, 0), &far)];
+        let idw = Idw {
+            power: 2.0,
+            min_pts: 2,
+        };
+        let (value, _) = idw
+            .augment_led(&Point::new(0, 0), &neighbors, 0, &Config::default())
+            .unwrap();
+        // Weights of 1/100 and 1/900
+        assert!((value - 1.2).abs() < 1e-5, "{}", value);
+        assert!(idw
+            .augment_led(&Point::new(0, 0), &neighbors[..1], 0, &Config::default())
+            .is_none());
+    }
+
+    #[test]
+    fn values_outside_the_field_of_view_are_not_sources() {
+        let (lit, dark) = (vec![1.0], vec![0.0]);
+        // The cone reaches about 176 mm from the LED at (0, 0)
+        let neighbors = [(Point::new(0, 0), &lit), (Point::new(300, 0), &dark)];
+        let idw = Idw {
+            power: 2.0,
+            min_pts: 1,
+        };
+        for (fov_reject_sources, expected) in [(false, 0.5), (true, 1.0)] {
+            let config = Config {
+                led_positions: vec![Position::new(0.0, 0.0)],
+                led_fovs: vec![0.1],
+                fov_reject_sources,
+                ..Config::default().select_leds(&[0])
+            };
+            let (value, uncertainty) = idw
+                .augment_led(&Point::new(150, 0), &neighbors, 0, &config)
+                .unwrap();
+            assert!((value - expected).abs() < 1e-5, "{}", value);
+            assert_eq!(uncertainty.n, if fov_reject_sources { 1 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn kriging_fits_a_variogram_per_led() {
+        // LED 0 is a smooth ramp, LED 1 a checkerboard without spatial correlation
+        let records = (0..=20)
+            .flat_map(|x| (0..=20).map(move |y| Point::new(10 * x, 10 * y)))
+            .map(|p| {
+                let checker = ((p.x + p.y) / 10 % 2) as f32;
+                RssRecord::new(p, vec![p.x as f32 / 10.0, 10.0 * checker])
+            })
+            .collect::<Vec<_>>();
+        let map = RssMap::from_records(&records, 10);
+        let config = Config {
+            augm_dist: 30,
+            ..Config::default().select_leds(&[0, 1])
+        };
+        let kriging = Kriging::fit(VariogramModel::Spherical, 16, 3, &map, &config);
+        let [smooth, rough] = [0, 1].map(|i| kriging.variograms[i]);
+        assert!(smooth.gamma(10.0) < smooth.gamma(50.0), "{:?}", smooth);
+        assert!(rough.nugget > smooth.nugget, "{:?} {:?}", rough, smooth);
+
+        let p = Point::new(100, 100);
+        let neighbors = map.in_range(&p, config.augm_dist);
+        let neighbors = neighbors
+            .into_iter()
+            .filter(|(q, _)| *q != p)
+            .collect::<Vec<_>>();
+        let (estimate, smooth_variance, _) = kriging.predict(&p, &neighbors, 0, &config).unwrap();
+        let (_, rough_variance, _) = kriging.predict(&p, &neighbors, 1, &config).unwrap();
+        assert!((estimate - 10.0).abs() < 0.1, "{}", estimate);
+        assert!(rough_variance > smooth_variance);
+    }
+}
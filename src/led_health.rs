@@ -0,0 +1,269 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    locate_leds::{neighborhood_medians, peak_position},
+    point::{Point, Position},
+    point_map::RssMap,
+};
+
+/// What to do with LEDs that fail the health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HealthAction {
+    /// Report faulty LEDs and keep them
+    Warn,
+    /// Report faulty LEDs and drop their columns from the output
+    Exclude,
+    /// Report faulty LEDs and stop
+    Abort,
+}
+
+/// Limits of the LED health check.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Values at or below this level count as dark
+    pub dead_level: f32,
+    /// An LED is dead if at least this fraction of its values is dark
+    pub dead_fraction: f32,
+    /// Saturation ceiling, the largest value of each LED if not set
+    pub saturation_level: Option<f32>,
+    /// An LED is saturated if more than this fraction of its values is at
+    /// the ceiling
+    pub saturated_fraction: f32,
+    /// Largest distance between the strongest values and the configured
+    /// LED position
+    pub max_peak_offset: f32,
+    /// Largest mean deviation from the neighborhood relative to the mean
+    /// deviation from the LED's overall mean; a smooth field is well below 1
+    /// while uncorrelated noise is around 1
+    pub max_roughness: f32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            dead_level: 1e-3,
+            dead_fraction: 0.99,
+            saturation_level: None,
+            saturated_fraction: 0.05,
+            max_peak_offset: 300.0,
+            max_roughness: 0.7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedFault {
+    /// Fraction of dark values
+    Dead(f32),
+    /// Fraction of values at the saturation ceiling
+    Saturated(f32),
+    /// Distance between the peak and the configured position
+    MisplacedPeak(f32),
+    /// Roughness of the field
+    Erratic(f32),
+}
+
+impl fmt::Display for LedFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedFault::Dead(fraction) => {
+                write!(f, "dead ({:.1}% of values dark)", fraction * 100.0)
+            }
+            LedFault::Saturated(fraction) => {
+                write!(f, "saturated ({:.1}% of values clipped)", fraction * 100.0)
+            }
+            LedFault::MisplacedPeak(offset) => {
+                write!(f, "peak {:.1} away from the configured position", offset)
+            }
+            LedFault::Erratic(roughness) => write!(f, "erratic (roughness {:.2})", roughness),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LedReport {
+    pub led: usize,
+    pub faults: Vec<LedFault>,
+}
+
+const ROUGHNESS_MAX_POINTS: usize = 2000;
+
+/// Checks every LED channel of `point_map` and returns the LEDs with at least
+/// one fault.
+pub fn check_leds(point_map: &RssMap, config: &Config) -> Vec<LedReport> {
+    let check = &config.led_health;
+    let points = point_map.points();
+    let mut samples = vec![Vec::new(); config.led_count];
+    for p in &points {
+        for rss in point_map.values(p) {
+            for (led, &v) in rss.iter().enumerate() {
+                if v.is_finite() {
+                    samples[led].push((Position::from(p), v));
+                }
+            }
+        }
+    }
+    let roughness = roughness(point_map, &points, config);
+
+    samples
+        .iter_mut()
+        .zip(roughness)
+        .enumerate()
+        .filter_map(|(led, (samples, roughness))| {
+            let faults = led_faults(led, samples, roughness, check, config);
+            (!faults.is_empty()).then_some(LedReport { led, faults })
+        })
+        .collect()
+}
+
+fn led_faults(
+    led: usize,
+    samples: &mut [(Position, f32)],
+    roughness: f32,
+    check: &HealthCheck,
+    config: &Config,
+) -> Vec<LedFault> {
+    let n = samples.len() as f32;
+    let dark = samples
+        .iter()
+        .filter(|(_, v)| *v <= check.dead_level)
+        .count() as f32;
+    let dark_fraction = if samples.is_empty() { 1.0 } else { dark / n };
+    if dark_fraction >= check.dead_fraction {
+        // Nothing else is meaningful for a dead LED
+        return vec![LedFault::Dead(dark_fraction)];
+    }
+
+    let mut faults = Vec::new();
+    samples.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    let ceiling = check.saturation_level.unwrap_or(samples[0].1);
+    let clipped = samples.iter().filter(|(_, v)| *v >= ceiling).count() as f32;
+    if clipped / n > check.saturated_fraction {
+        faults.push(LedFault::Saturated(clipped / n));
+    }
+
+    // Spikes would pull the strongest raw values away from the peak
+    let mut footprint = neighborhood_medians(
+        &samples
+            .iter()
+            .map(|(p, v)| (p.x as f64, p.y as f64, *v as f64))
+            .collect::<Vec<_>>(),
+        config,
+    );
+    footprint.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    let peak = peak_position(footprint.iter().map(|(p, v)| (p, *v)));
+    let offset = peak.dist(&config.led_positions[led]);
+    if offset > check.max_peak_offset {
+        faults.push(LedFault::MisplacedPeak(offset));
+    }
+
+    if roughness > check.max_roughness {
+        faults.push(LedFault::Erratic(roughness));
+    }
+    faults
+}
+
+/// Per LED, the mean absolute deviation of values from the mean of the other
+/// points around them divided by their mean absolute deviation from the
+/// overall mean.
+fn roughness(point_map: &RssMap, points: &[Point], config: &Config) -> Vec<f32> {
+    let mut sums = vec![(0.0, 0.0, 0.0, 0_usize); config.led_count];
+    let stride = (points.len() / ROUGHNESS_MAX_POINTS).max(1);
+    let mut local = Vec::new();
+    for p in points.iter().step_by(stride) {
+        let neighbors = point_map
+            .in_range(p, config.clean_dist)
+            .into_iter()
+            .filter(|(q, _)| q != p)
+            .collect::<Vec<_>>();
+        for rss in point_map.values(p) {
+            for (led, &v) in rss.iter().enumerate() {
+                if !v.is_finite() {
+                    continue;
+                }
+                let (sum, count) = neighbors
+                    .iter()
+                    .map(|(_, n)| n[led])
+                    .filter(|n| n.is_finite())
+                    .fold((0.0, 0), |(sum, count), n| (sum + n, count + 1));
+                if count < 2 {
+                    continue;
+                }
+                local.push((led, v, sum / count as f32));
+            }
+        }
+    }
+    for &(led, v, _) in &local {
+        sums[led].0 += v;
+        sums[led].3 += 1;
+    }
+    for &(led, v, neighborhood) in &local {
+        let mean = sums[led].0 / sums[led].3 as f32;
+        sums[led].1 += (v - neighborhood).abs();
+        sums[led].2 += (v - mean).abs();
+    }
+    sums.iter()
+        .map(|&(_, local_dev, global_dev, _)| {
+            if global_dev > 0.0 {
+                local_dev / global_dev
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{augment::predict_rss, rss_record::RssRecord};
+
+    #[test]
+    fn roughness_compares_values_with_the_other_points() {
+        let config = Config {
+            led_count: 1,
+            clean_dist: 10,
+            ..Config::default()
+        };
+        // Checkerboard: every value differs from all of its neighbors by one
+        let records = (0..10)
+            .flat_map(|x| (0..10).map(move |y| (x, y)))
+            .map(|(x, y)| RssRecord::new(Point::new(10 * x, 10 * y), vec![((x + y) % 2) as f32]))
+            .collect::<Vec<_>>();
+        let point_map = RssMap::from_records(&records, 10);
+        let roughness = roughness(&point_map, &point_map.points(), &config);
+        assert!((roughness[0] - 2.0).abs() < 1e-6, "{:?}", roughness);
+    }
+
+    #[test]
+    fn spikes_do_not_misplace_the_peak() {
+        let config = Config {
+            clean_dist: 40,
+            grid_resolution: 20,
+            ..Config::default().select_leds(&[0])
+        };
+        let led = config.led_positions[0];
+        let peak = predict_rss(&Point::new(led.x as i64, led.y as i64), 0, &config);
+        // Isolated spikes above the peak, all on the far side of the survey
+        let records = (0..=50)
+            .flat_map(|x| (0..=50).map(move |y| Point::new(20 * x, 20 * y)))
+            .enumerate()
+            .map(|(k, p)| {
+                let v = if k % 37 == 0 && p.x >= 700 {
+                    2.0
+                } else {
+                    predict_rss(&p, 0, &config) / peak
+                };
+                RssRecord::new(p, vec![v])
+            })
+            .collect::<Vec<_>>();
+        let point_map = RssMap::from_records(&records, config.grid_resolution);
+        let reports = check_leds(&point_map, &config);
+        assert!(reports.is_empty(), "{:?}", reports);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{collections::BTreeMap, error::Error, path::Path};
+
+use clap::ValueEnum;
+
+use crate::{
+    augment::current_rss,
+    config::Config,
+    open_output,
+    point::Position,
+    point_map::RssMap,
+    rss_record::{by_room, read_radio_map, RssArr, RssRecord},
+};
+
+/// Dissimilarity of two RSS vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Metric {
+    Euclidean,
+    Manhattan,
+    /// One minus the cosine similarity
+    Cosine,
+    /// Euclidean distance of the logarithms, comparing ratios instead of
+    /// differences
+    Log,
+}
+
+/// Smallest RSS considered by the log metric.
+const LOG_FLOOR: f32 = 1e-6;
+
+impl Metric {
+    /// Dissimilarity over the LEDs with a value in both vectors, scaled to
+    /// all LEDs. Infinite if no LED has a value in both.
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        let pairs = a
+            .iter()
+            .zip(b)
+            .filter(|(a, b)| a.is_finite() && b.is_finite())
+            .map(|(a, b)| (*a, *b))
+            .collect::<Vec<_>>();
+        if pairs.is_empty() {
+            return f32::INFINITY;
+        }
+        let scale = a.len() as f32 / pairs.len() as f32;
+        match self {
+            Metric::Euclidean => {
+                (scale * pairs.iter().map(|(a, b)| (a - b) * (a - b)).sum::<f32>()).sqrt()
+            }
+            Metric::Manhattan => scale * pairs.iter().map(|(a, b)| (a - b).abs()).sum::<f32>(),
+            Metric::Cosine => {
+                let dot = pairs.iter().map(|(a, b)| a * b).sum::<f32>();
+                let norm_a = pairs.iter().map(|(a, _)| a * a).sum::<f32>().sqrt();
+                let norm_b = pairs.iter().map(|(_, b)| b * b).sum::<f32>().sqrt();
+                if norm_a == 0.0 || norm_b == 0.0 {
+                    return 1.0;
+                }
+                1.0 - dot / (norm_a * norm_b)
+            }
+            Metric::Log => {
+                let ln = |v: f32| v.max(LOG_FLOOR).ln();
+                (scale
+                    * pairs
+                        .iter()
+                        .map(|(a, b)| (ln(*a) - ln(*b)) * (ln(*a) - ln(*b)))
+                        .sum::<f32>())
+                .sqrt()
+            }
+        }
+    }
+}
+
+/// RSS vector of a known position of the radio map.
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    pub position: Position,
+    pub room: Option<String>,
+    pub rss: RssArr,
+}
+
+/// Estimated position of a query.
+#[derive(Debug, Clone)]
+pub struct Estimate {
+    pub position: Position,
+    pub room: Option<String>,
+    /// Distance to the nearest fingerprint
+    pub distance: f32,
+}
+
+/// Loads the radio map at `path` and returns its fingerprints.
+pub fn load_fingerprints(path: &Path) -> Result<Vec<Fingerprint>, Box<dyn Error>> {
+    Ok(fingerprints(read_radio_map(path)?))
+}
+
+/// Fingerprints of the radio map `records`, loaded into one `RssMap` per
+/// room. Records of the same point are averaged.
+pub fn fingerprints(records: Vec<RssRecord>) -> Vec<Fingerprint> {
+    let mut fingerprints = Vec::new();
+    for (room, records) in by_room(records) {
+        // Radio maps of any grid resolution are accepted
+        let point_map = RssMap::from_records(&records, 1);
+        for p in point_map.points() {
+            if let Some(rss) = current_rss(&p, &point_map) {
+                fingerprints.push(Fingerprint {
+                    position: Position::from(&p),
+                    room: room.clone(),
+                    rss,
+                });
+            }
+        }
+    }
+    fingerprints
+}
+
+/// k-nearest-neighbor fingerprinting.
+#[derive(Debug, Clone, Copy)]
+pub struct Knn {
+    pub k: usize,
+    pub metric: Metric,
+    /// Weights the nearest fingerprints by inverse distance
+    pub weighted: bool,
+}
+
+impl Knn {
+    /// Estimates the position of `query` from its nearest fingerprints. The
+    /// room is the one with the largest total weight.
+    pub fn locate(&self, query: &[f32], fingerprints: &[Fingerprint]) -> Option<Estimate> {
+        let mut nearest = fingerprints
+            .iter()
+            .map(|f| (self.metric.distance(query, &f.rss), f))
+            .filter(|(d, _)| d.is_finite())
+            .collect::<Vec<_>>();
+        nearest.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        nearest.truncate(self.k.max(1));
+        let distance = nearest.first()?.0;
+
+        let weighted = self.weighted;
+        let weight = |d: f32| if weighted { 1.0 / d.max(1e-6) } else { 1.0 };
+        let mut rooms = BTreeMap::<_, f32>::new();
+        let (sx, sy, sw) = nearest
+            .iter()
+            .fold((0.0, 0.0, 0.0), |(sx, sy, sw), (d, f)| {
+                let w = weight(*d);
+                *rooms.entry(&f.room).or_default() += w;
+                (sx + w * f.position.x, sy + w * f.position.y, sw + w)
+            });
+        let room = rooms
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .and_then(|(room, _)| room.clone());
+        Some(Estimate {
+            position: Position::new(sx / sw, sy / sw),
+            room,
+            distance,
+        })
+    }
+}
+
+/// Query RSS vector with the identifier of its row.
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub id: String,
+    pub rss: RssArr,
+}
+
+/// Reads queries from a CSV with `led_<i>` columns and an optional `id`
+/// column. Queries without an `id` are identified by their row.
+pub fn read_queries(path: &Path, led_count: usize) -> Result<Vec<Query>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let id = column("id");
+    let leds = (0..led_count)
+        .map(|i| column(&format!("led_{}", i)))
+        .collect::<Vec<_>>();
+
+    let mut queries = Vec::new();
+    for (row, record) in rdr.records().enumerate() {
+        let record = record?;
+        let value = |idx: Option<usize>| -> Result<Option<f32>, Box<dyn Error>> {
+            match idx
+                .and_then(|idx| record.get(idx))
+                .filter(|v| !v.is_empty())
+            {
+                Some(v) => Ok(Some(v.parse::<f32>()?)),
+                None => Ok(None),
+            }
+        };
+        let rss = leds
+            .iter()
+            .map(|&idx| Ok(value(idx)?.unwrap_or(f32::NAN)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        queries.push(Query {
+            id: id
+                .and_then(|idx| record.get(idx))
+                .map_or_else(|| row.to_string(), ToOwned::to_owned),
+            rss,
+        });
+    }
+    Ok(queries)
+}
+
+pub fn run(
+    radio_map: &Path,
+    queries: &Path,
+    output: Option<&Path>,
+    knn: &Knn,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let fingerprints = load_fingerprints(radio_map)?;
+    let queries = read_queries(queries, config.led_count)?;
+    let rooms = fingerprints.iter().any(|f| f.room.is_some());
+
+    let mut wtr = csv::Writer::from_writer(open_output(output)?);
+    let mut headers = vec!["id", "x", "y"];
+    if rooms {
+        headers.push("room");
+    }
+    headers.push("distance");
+    wtr.write_record(headers)?;
+    for query in &queries {
+        let mut row = vec![query.id.clone()];
+        match knn.locate(&query.rss, &fingerprints) {
+            Some(estimate) => {
+                row.push(estimate.position.x.to_string());
+                row.push(estimate.position.y.to_string());
+                if rooms {
+                    row.push(estimate.room.unwrap_or_default());
+                }
+                row.push(estimate.distance.to_string());
+            }
+            None => {
+                row.extend(["NaN".to_owned(), "NaN".to_owned()]);
+                if rooms {
+                    row.push(String::new());
+                }
+                row.push("NaN".to_owned());
+            }
+        }
+        wtr.write_record(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn radio_map_positions_are_not_rescaled() {
+        let path = std::env::temp_dir().join("locate_radio_map_test.csv");
+        std::fs::write(
+            &path,
+            "x,y,led_0,led_1\n0,0,1,2\n2500,10,4,6\n2500,10,2,NaN\n",
+        )
+        .unwrap();
+        let fingerprints = load_fingerprints(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(fingerprints.len(), 2);
+        let duplicate = &fingerprints[1];
+        assert_eq!(duplicate.position, Position::new(2500.0, 10.0));
+        assert_eq!(duplicate.rss, vec![3.0, 6.0]);
+    }
+
+    #[test]
+    fn leds_after_a_gap_keep_their_index() {
+        let dir = std::env::temp_dir();
+        let (map, queries) = (
+            dir.join("locate_gapped_map_test.csv"),
+            dir.join("locate_gapped_queries_test.csv"),
+        );
+        std::fs::write(&map, "x,y,led_0,led_2\n0,0,1,9\n").unwrap();
+        std::fs::write(&queries, "led_0,led_1,led_2\n1,5,9\n").unwrap();
+        let fingerprints = load_fingerprints(&map).unwrap();
+        let query = read_queries(&queries, 3).unwrap().remove(0);
+        std::fs::remove_file(&map).unwrap();
+        std::fs::remove_file(&queries).unwrap();
+
+        assert_eq!(fingerprints[0].rss[0], 1.0);
+        assert!(fingerprints[0].rss[1].is_nan());
+        assert_eq!(fingerprints[0].rss[2], 9.0);
+        let knn = Knn {
+            k: 1,
+            metric: Metric::Euclidean,
+            weighted: false,
+        };
+        let estimate = knn.locate(&query.rss, &fingerprints).unwrap();
+        assert_eq!(estimate.distance, 0.0);
+    }
+}
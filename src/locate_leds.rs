@@ -0,0 +1,265 @@
+use std::{error::Error, io::Write, path::Path};
+
+use clap::ValueEnum;
+
+use crate::{
+    calibrate::{fit_led, led_samples, PARAM_COUNT},
+    clean::median,
+    config::{read_table, room_table, set_key, Config},
+    open_output,
+    point::{Point, Position},
+    point_map::PointMap,
+    rss_record::{by_room, read_records, RssRecord},
+};
+
+/// How the position of an LED is estimated from its footprint, the median
+/// of the values around each sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LocateMethod {
+    /// Centroid of the strongest values
+    Peak,
+    /// RSS-weighted centroid of the values above half the maximum
+    Centroid,
+    /// Position of the fitted channel model
+    Model,
+}
+
+/// Fraction of the strongest values whose positions locate the peak.
+pub const PEAK_FRACTION: f32 = 0.01;
+
+/// Centroid of the positions of the strongest `PEAK_FRACTION` of the values,
+/// which must be sorted in decreasing order.
+pub fn peak_position<'a>(sorted: impl ExactSizeIterator<Item = (&'a Position, f32)>) -> Position {
+    let top = ((sorted.len() as f32 * PEAK_FRACTION).ceil() as usize).max(1);
+    let (sx, sy, n) = sorted.take(top).fold((0.0, 0.0, 0), |(sx, sy, n), (p, _)| {
+        (sx + p.x, sy + p.y, n + 1)
+    });
+    Position::new(sx / n as f32, sy / n as f32)
+}
+
+/// Position of every sample with the median of the values within
+/// `clean_dist` of it, so that isolated spikes and dropouts do not shape the
+/// footprint.
+pub(crate) fn neighborhood_medians(
+    samples: &[(f64, f64, f64)],
+    config: &Config,
+) -> Vec<(Position, f32)> {
+    let point_map = PointMap::with_inferred_bounds(
+        samples
+            .iter()
+            .map(|&(x, y, rss)| (Point::new(x as i64, y as i64), rss as f32))
+            .collect(),
+        config.grid_resolution,
+    );
+    samples
+        .iter()
+        .map(|&(x, y, _)| {
+            let p = Point::new(x as i64, y as i64);
+            let mut values = point_map
+                .in_range(&p, config.clean_dist)
+                .into_iter()
+                .map(|(_, &v)| v)
+                .collect::<Vec<_>>();
+            values.sort_by(f32::total_cmp);
+            (Position::from(&p), median(&values))
+        })
+        .collect()
+}
+
+fn locate_led(
+    samples: &[(f64, f64, f64)],
+    led_idx: usize,
+    method: LocateMethod,
+    max_iters: usize,
+    config: &Config,
+) -> Option<Position> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = neighborhood_medians(samples, config);
+    sorted.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    match method {
+        LocateMethod::Peak => Some(peak_position(sorted.iter().map(|(p, v)| (p, *v)))),
+        LocateMethod::Centroid => {
+            let half_max = sorted[0].1 / 2.0;
+            let (sx, sy, sw) = sorted
+                .iter()
+                .take_while(|(_, v)| *v >= half_max)
+                .fold((0.0, 0.0, 0.0), |(sx, sy, sw), (p, v)| {
+                    (sx + v * p.x, sy + v * p.y, sw + v)
+                });
+            (sw > 0.0).then(|| Position::new(sx / sw, sy / sw))
+        }
+        LocateMethod::Model if samples.len() > PARAM_COUNT => {
+            let fit = fit_led(samples, led_idx, max_iters, config);
+            Some(Position::new(fit.params[2] as f32, fit.params[3] as f32))
+        }
+        LocateMethod::Model => None,
+    }
+}
+
+/// Sets the LED positions in the configuration `table`.
+fn set_positions(
+    table: &mut toml::Table,
+    positions: &[Position],
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let positions = positions
+        .iter()
+        .zip(&config.led_heights)
+        .map(|(p, &z)| toml::Value::try_from([p.x, p.y, z]))
+        .collect::<Result<Vec<_>, _>>()?;
+    set_key(table, "led_positions", toml::Value::Array(positions));
+    Ok(())
+}
+
+/// Locates the LEDs of each room with the room's configuration and writes
+/// the configuration file at `config_path` with the located positions, those
+/// of each room in its `[rooms.<id>]` table.
+pub fn run(
+    input: &Path,
+    output: Option<&Path>,
+    method: LocateMethod,
+    max_iters: usize,
+    config_path: Option<&Path>,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut table = read_table(config_path)?;
+    for (room, records) in by_room(read_records(input, config)?) {
+        if let Some(room) = &room {
+            eprintln!("Locating the LEDs of room {}", room);
+        }
+        let config = config.for_room(room.as_deref())?;
+        let positions = locate_leds(&records, method, max_iters, config);
+        set_positions(room_table(&mut table, room.as_deref())?, &positions, config)?;
+    }
+    let mut output = open_output(output)?;
+    output.write_all(toml::to_string(&table)?.as_bytes())?;
+    Ok(())
+}
+
+/// Positions of the LEDs located from `records`. LEDs that cannot be located
+/// keep their configured position.
+fn locate_leds(
+    records: &[RssRecord],
+    method: LocateMethod,
+    max_iters: usize,
+    config: &Config,
+) -> Vec<Position> {
+    let mut positions = config.led_positions.clone();
+    eprintln!("led,samples,configured_x,configured_y,x,y,offset");
+    for (i, position) in positions.iter_mut().enumerate() {
+        let samples = led_samples(records, i);
+        let configured = *position;
+        let Some(located) = locate_led(&samples, i, method, max_iters, config) else {
+            eprintln!(
+                "{},{},{},{},NaN,NaN,NaN",
+                i,
+                samples.len(),
+                configured.x,
+                configured.y
+            );
+            continue;
+        };
+        eprintln!(
+            "{},{},{},{},{},{},{}",
+            i,
+            samples.len(),
+            configured.x,
+            configured.y,
+            located.x,
+            located.y,
+            located.dist(&configured),
+        );
+        *position = located;
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{augment::predict_rss, point::Point};
+
+    #[test]
+    fn isolated_outliers_do_not_move_the_footprint() {
+        let truth = Config {
+            led_positions: vec![Position::new(1230.0, 990.0)],
+            clean_dist: 40,
+            grid_resolution: 20,
+            ..Config::default().select_leds(&[0])
+        };
+        let peak = predict_rss(&Point::new(1230, 990), 0, &truth);
+        let records = (0..=100)
+            .flat_map(|x| (0..=100).map(move |y| Point::new(20 * x, 20 * y)))
+            .enumerate()
+            .map(|(k, p)| {
+                // Every 50th value is a spike far above the peak or a dropout
+                let v = match k % 100 {
+                    0 => 3.0 * peak,
+                    50 => 0.0,
+                    _ => predict_rss(&p, 0, &truth),
+                };
+                RssRecord::new(p, vec![v])
+            })
+            .collect::<Vec<_>>();
+        let config = Config {
+            led_positions: vec![Position::new(1000.0, 1000.0)],
+            ..truth.clone()
+        };
+        for method in [LocateMethod::Peak, LocateMethod::Centroid] {
+            let located = locate_leds(&records, method, 100, &config);
+            assert!(
+                located[0].dist(&truth.led_positions[0]) < 20.0,
+                "{:?}: {:?}",
+                method,
+                located
+            );
+        }
+    }
+
+    #[test]
+    fn corrected_positions_are_written_per_room() {
+        let text = "led_count = 1\nled_positions = [[1000.5, 999.75]]\n[rooms.a]\n";
+        let config = Config::from_toml(text, Path::new(".")).unwrap();
+        let truth = Config {
+            led_positions: vec![Position::new(1234.5, 987.25)],
+            ..config.clone()
+        };
+        let dir = std::env::temp_dir().join("process_data_locate_leds_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("survey.csv"), dir.join("located.toml"));
+        let mut wtr = csv::Writer::from_path(&input).unwrap();
+        wtr.write_record(["x", "y", "room", "led_0"]).unwrap();
+        for x in 0..=50 {
+            for y in 0..=50 {
+                let p = Point::new(750 + 20 * x, 500 + 20 * y);
+                let rss = predict_rss(&p, 0, &truth).to_string();
+                wtr.write_record([&p.x.to_string(), &p.y.to_string(), "a", &rss])
+                    .unwrap();
+            }
+        }
+        wtr.flush().unwrap();
+        let config_path = dir.join("conf.toml");
+        std::fs::write(&config_path, text).unwrap();
+
+        run(
+            &input,
+            Some(&output),
+            LocateMethod::Centroid,
+            100,
+            Some(&config_path),
+            &config,
+        )
+        .unwrap();
+        let written = Config::from_file(&output).unwrap();
+        // Only the room's LED moves, the others keep their decimals
+        assert_eq!(written.led_positions, config.led_positions);
+        let located = written.for_room(Some("a")).unwrap().led_positions[0];
+        assert!(
+            located.dist(&truth.led_positions[0]) < 20.0,
+            "{:?}",
+            located
+        );
+    }
+}
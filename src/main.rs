@@ -1,33 +1,96 @@
-use augment::AugmentBox;
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressIterator};
-use point::Point;
-use std::{error::Error, io, path::PathBuf};
+use std::{
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+};
 
+use augment::{AugmentOptions, AugmentStrategy};
 use clean::{clean_records_stg1, clean_records_stg2};
 use config::Config;
-use rss_record::RssRecord;
+use evaluate::{Split, SplitMethod};
+use led_health::HealthAction;
+use locate::{Knn, Metric};
+use locate_leds::LocateMethod;
+use rss_record::{ExtraColumns, RssRecord};
+use simulate::{Simulation, SurveyMode};
 
 mod augment;
+mod bench_augment;
+mod calibrate;
 mod clean;
+mod clean_report;
 mod config;
+mod evaluate;
+mod fit;
+mod interpolate;
+mod led_health;
+mod locate;
+mod locate_leds;
 mod point;
 mod point_map;
+mod propagation;
 mod rss_record;
+mod simulate;
+mod trilaterate;
 
 #[derive(Debug, Parser)]
+#[command(
+    subcommand_negates_reqs = true,
+    group(ArgGroup::new("estimating").args(["clean", "augment"]).multiple(true))
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file
-    input: PathBuf,
+    #[arg(required = true)]
+    input: Option<PathBuf>,
 
     /// Output file, prints to stdout if not present
     #[arg(short, long, value_name = "OUT_FILE")]
     output: Option<PathBuf>,
 
     /// Configuration file, uses default values for values not present
-    #[arg(short, long, value_name = "CONFIG_FILE")]
+    #[arg(short, long, value_name = "CONFIG_FILE", global = true)]
     config: Option<PathBuf>,
 
+    /// Specifies whether to write the uncertainty of cleaned and augmented values as extra
+    /// columns
+    #[arg(long, requires = "estimating")]
+    uncertainty: bool,
+
+    /// Specifies whether to write the provenance of every value as extra columns
+    #[arg(long)]
+    provenance: bool,
+
+    // Last, since its help heading also applies to the arguments following it
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+}
+
+// A doc comment would replace the about text of the commands flattening it
+#[derive(Debug, Args)]
+#[command(
+    next_help_heading = "Checking, cleaning and augmentation",
+    group(
+        ArgGroup::new("augment_options")
+            .args([
+                "augment_strategy",
+                "augment_max_iters",
+                "augment_tolerance",
+                "augment_refine"
+            ])
+            .multiple(true)
+            .requires("augment")
+    )
+)]
+pub struct PipelineArgs {
+    /// Checks for dead, saturated, misplaced or erratic LEDs before processing
+    #[arg(long, value_enum, value_name = "ACTION")]
+    check_leds: Option<HealthAction>,
+
     /// Specifies whether to clean the data
     #[arg(long)]
     clean: bool,
@@ -41,84 +104,690 @@ struct Cli {
     )]
     clean_augment_iters: u32,
 
+    /// Writes an audit of the stage 1 cleaning decisions, as JSON if the file
+    /// ends in `.json` and as CSV otherwise
+    #[arg(long, value_name = "REPORT_FILE", requires = "clean")]
+    clean_report: Option<PathBuf>,
+
     /// Specifies whether to augment the data
     #[arg(long)]
     augment: bool,
+
+    #[command(flatten)]
+    augment_args: AugmentArgs,
 }
 
-fn aug_boxes() -> Vec<AugmentBox> {
-    vec![
-        AugmentBox::new_with_size(Point::new(0, 0), 1210, 1210),
-        AugmentBox::new_with_size(Point::new(1610, 0), 1210, 1210),
-        AugmentBox::new_with_size(Point::new(0, 1550), 1210, 1210),
-        AugmentBox::new_with_size(Point::new(1610, 1550), 1210, 1210),
-    ]
+// Shared by the pipeline and the augmentation benchmark
+#[derive(Debug, Default, Args)]
+pub struct AugmentArgs {
+    /// Specifies how missing values are augmented, overriding the configured interpolation method
+    #[arg(long, value_enum, value_name = "STRATEGY")]
+    augment_strategy: Option<AugmentStrategy>,
+
+    /// Maximum number of augmentation iterations, overriding the configured value
+    #[arg(long = "aug-max-iters", value_name = "COUNT")]
+    augment_max_iters: Option<usize>,
+
+    /// Largest change of an augmented value still considered converged,
+    /// overriding the configured value
+    #[arg(long = "aug-tolerance", value_name = "TOLERANCE")]
+    augment_tolerance: Option<f32>,
+
+    /// Re-estimates augmented values in later iterations instead of keeping
+    /// them as first filled
+    #[arg(long = "aug-refine")]
+    augment_refine: bool,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+impl AugmentArgs {
+    /// Augmentation options, the configured values where none were given.
+    fn options(&self, config: &Config) -> AugmentOptions {
+        AugmentOptions {
+            strategy: self.augment_strategy,
+            max_iters: self.augment_max_iters.unwrap_or(config.augm_max_iters),
+            tolerance: self.augment_tolerance.unwrap_or(config.augm_tolerance),
+            refine: self.augment_refine || config.augm_refine,
+        }
+    }
+}
 
-    let config = if let Some(config_path) = cli.config {
-        Config::from_file(&config_path)?
-    } else {
-        Config::default()
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Fits per-LED channel parameters to (cleaned) measurements
+    Calibrate {
+        /// Input file
+        input: PathBuf,
+
+        /// Output TOML file with the configuration and the fitted parameters,
+        /// prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// Maximum number of solver iterations per LED
+        #[arg(long, default_value_t = 200, value_name = "COUNT")]
+        max_iters: usize,
+    },
+    /// Estimates the LED positions from their measured footprint and writes
+    /// a configuration with the corrected positions
+    LocateLeds {
+        /// Input file
+        input: PathBuf,
+
+        /// Output TOML file, prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// How the LED positions are estimated
+        #[arg(long, value_enum, default_value_t = LocateMethod::Centroid)]
+        method: LocateMethod,
+
+        /// Maximum number of solver iterations per LED for the model method
+        #[arg(long, default_value_t = 200, value_name = "COUNT")]
+        max_iters: usize,
+    },
+    /// Estimates the positions of query RSS vectors from a radio map with
+    /// k-nearest-neighbor fingerprinting
+    Locate {
+        /// Radio map, e.g. the cleaned and augmented output
+        radio_map: PathBuf,
+
+        /// Query file with `led_<i>` columns and an optional `id` column
+        queries: PathBuf,
+
+        /// Output file, prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// Number of nearest fingerprints
+        #[arg(short, default_value_t = 4)]
+        k: usize,
+
+        /// Dissimilarity of RSS vectors
+        #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+        metric: Metric,
+
+        /// Weights the nearest fingerprints by inverse distance (WkNN)
+        #[arg(long)]
+        weighted: bool,
+    },
+    /// Generates a synthetic survey of the augment boxes from the configured
+    /// LEDs and channel model
+    Simulate {
+        /// Output file with the simulated measurements, positioned in the
+        /// `input_unit` like real ones, prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// Output file with the noise-free RSS of every grid point, positioned
+        /// in the `unit` of the grid like the radio map
+        #[arg(long, value_name = "TRUTH_FILE")]
+        truth: Option<PathBuf>,
+
+        /// How the survey visits the area
+        #[arg(long, value_enum, default_value_t = SurveyMode::Grid)]
+        survey: SurveyMode,
+
+        /// Number of walks of the path survey
+        #[arg(long, default_value_t = 10, value_name = "COUNT")]
+        paths: usize,
+
+        /// Distance between the samples of a walk, the grid resolution if not present
+        #[arg(long, value_name = "DISTANCE")]
+        step: Option<f32>,
+
+        /// Samples taken at every surveyed position
+        #[arg(long, default_value_t = 1, value_name = "COUNT")]
+        samples: usize,
+
+        /// Standard deviation of the additive Gaussian noise
+        #[arg(long, default_value_t = 0.0, value_name = "STD")]
+        noise_std: f32,
+
+        /// Shot noise factor, the noise variance grows by this factor times the RSS
+        #[arg(long, default_value_t = 0.0, value_name = "FACTOR")]
+        shot_noise: f32,
+
+        /// Fraction of the values replaced by outliers
+        #[arg(long, default_value_t = 0.0, value_name = "FRACTION")]
+        outlier_fraction: f32,
+
+        /// Number of circular regions without measurements
+        #[arg(long, default_value_t = 0, value_name = "COUNT")]
+        dead_regions: usize,
+
+        /// Radius of the regions without measurements, the augmentation
+        /// distance if not present
+        #[arg(long, value_name = "RADIUS")]
+        dead_radius: Option<f32>,
+
+        /// Seed of the random noise and survey
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Measures the accuracy of the augmentation by hiding measured points
+    /// and filling them again
+    BenchAugment {
+        /// Input file with the measurements
+        input: PathBuf,
+
+        /// Output file for the errors by distance to the nearest remaining
+        /// measurement, prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// How measured points are split into remaining and hidden points
+        #[arg(long, value_enum, default_value_t = SplitMethod::Random)]
+        split: SplitMethod,
+
+        /// Fraction of the points hidden by the random and block splits
+        #[arg(long, default_value_t = 0.2, value_name = "FRACTION")]
+        test_fraction: f32,
+
+        /// Number of folds of the k-fold split
+        #[arg(long, default_value_t = 5, value_name = "COUNT")]
+        folds: usize,
+
+        /// Side of the blocks of the block split, twice the augmentation
+        /// distance if not present
+        #[arg(long, value_name = "SIZE")]
+        block_size: Option<usize>,
+
+        /// Seed of the random split
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Width of the distance bins, the grid resolution if not present
+        #[arg(long, value_name = "WIDTH")]
+        bin_width: Option<usize>,
+
+        #[command(flatten)]
+        augment_args: AugmentArgs,
+    },
+    /// Measures the positioning accuracy of radio maps built from part of
+    /// the measurements
+    Evaluate {
+        /// Input file with the measurements
+        input: PathBuf,
+
+        /// Output file for the error CDF, prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// How measured points are split into training and test points
+        #[arg(long, value_enum, default_value_t = SplitMethod::Random)]
+        split: SplitMethod,
+
+        /// Fraction of the points used for testing by the random and block splits
+        #[arg(long, default_value_t = 0.2, value_name = "FRACTION")]
+        test_fraction: f32,
+
+        /// Number of folds of the k-fold split
+        #[arg(long, default_value_t = 5, value_name = "COUNT")]
+        folds: usize,
+
+        /// Side of the blocks of the block split, twice the augmentation
+        /// distance if not present
+        #[arg(long, value_name = "SIZE")]
+        block_size: Option<usize>,
+
+        /// Seed of the random split
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Number of nearest fingerprints
+        #[arg(short, default_value_t = 4)]
+        k: usize,
+
+        /// Dissimilarity of RSS vectors
+        #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+        metric: Metric,
+
+        /// Weights the nearest fingerprints by inverse distance (WkNN)
+        #[arg(long)]
+        weighted: bool,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+    },
+    /// Estimates the positions of query RSS vectors by inverting the channel
+    /// model of the strongest LEDs
+    Trilaterate {
+        /// Query file with `led_<i>` columns and an optional `id` column
+        queries: PathBuf,
+
+        /// Output file, prints to stdout if not present
+        #[arg(short, long, value_name = "OUT_FILE")]
+        output: Option<PathBuf>,
+
+        /// Number of strongest LEDs used per query
+        #[arg(long, default_value_t = 6, value_name = "COUNT")]
+        leds: usize,
+
+        /// Also estimates the receiver height
+        #[arg(long)]
+        solve_height: bool,
+
+        /// Maximum number of solver iterations per query
+        #[arg(long, default_value_t = 100, value_name = "COUNT")]
+        max_iters: usize,
+    },
+}
+
+pub fn open_output(path: Option<&Path>) -> io::Result<Box<dyn io::Write>> {
+    match path {
+        Some(path) => Ok(Box::new(std::fs::File::create(path)?)),
+        None => Ok(Box::new(io::stdout())),
+    }
+}
+
+/// Path of the clean report of `room`, which is `path` itself without rooms.
+fn room_report_path(path: &Path, room: Option<&str>) -> PathBuf {
+    let Some(room) = room else {
+        return path.to_owned();
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, room, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, room),
     };
+    path.with_file_name(name)
+}
 
-    let mut rdr = csv::Reader::from_path(cli.input)?;
+/// Checks, cleans and augments the records of one room. Returns the records
+/// and the LEDs to exclude from the output.
+fn process_room(
+    mut records: Vec<RssRecord>,
+    room: Option<&str>,
+    config: &Config,
+    cli: &PipelineArgs,
+) -> Result<(Vec<RssRecord>, Vec<usize>), Box<dyn Error>> {
+    let mut excluded = Vec::new();
+    if let Some(action) = cli.check_leds {
+        let point_map = point_map::RssMap::from_records(&records, config.grid_resolution);
+        let reports = led_health::check_leds(&point_map, config);
+        for report in &reports {
+            let faults = report
+                .faults
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            eprintln!("LED {}: {}", report.led, faults.join(", "));
+        }
+        match action {
+            HealthAction::Abort if !reports.is_empty() => {
+                return Err(format!("{} faulty LED(s) found", reports.len()).into());
+            }
+            HealthAction::Exclude => excluded = reports.iter().map(|r| r.led).collect(),
+            _ => {}
+        }
+    }
 
-    let mut records = rdr
-        .deserialize()
-        .collect::<Result<Vec<RssRecord>, csv::Error>>()?;
+    // Excluded LEDs take no part in cleaning and augmentation
+    let led_count = config.led_count;
+    let active = (0..led_count)
+        .filter(|i| !excluded.contains(i))
+        .collect::<Vec<_>>();
+    let config = &config.select_leds(&active);
+    records = records.iter().map(|r| r.select_leds(&active)).collect();
 
     if cli.clean {
-        records = clean_records_stg1(records, &config);
+        let (cleaned, mut audits) = clean_records_stg1(records, config, cli.clean_report.is_some());
+        records = cleaned;
+        for audit in &mut audits {
+            audit.led = active[audit.led];
+        }
+        if let Some(path) = &cli.clean_report {
+            clean_report::write_report(&room_report_path(path, room), &audits, config)?;
+        }
         let num_iters = cli.clean_augment_iters;
         let iter_pb = ProgressBar::new(num_iters as u64).with_style(config::pb_style2());
         iter_pb.set_message("Cleaning data (stage 2)");
         for _ in (0..cli.clean_augment_iters).progress_with(iter_pb) {
-            records = clean_records_stg2(records, &config);
+            records = clean_records_stg2(records, config);
         }
     }
+    // Shares of split measurements that were not cleaned are averaged now
+    records = rss_record::merge_shares(records);
 
     if cli.augment {
-        let mut point_map = point_map::PointMap::from_raw_records(records.clone());
-        augment::populate_points(&mut point_map, &aug_boxes(), &config);
-        let to_augment = point_map.all_points();
-        let augment_pb = ProgressBar::new(to_augment.len() as u64).with_style(config::pb_style());
-        augment_pb.set_message("Augmenting data");
-        for it in 0..20 {
-            augment_pb.reset();
-            records = to_augment
-                .iter()
-                .progress_with(augment_pb.clone())
-                .map(|p| RssRecord {
-                    point: *p,
-                    rss: augment::augment_point(p, &point_map, &config, config.augm_min_neighbors2),
-                })
-                .collect();
-            point_map = point_map::PointMap::from_raw_records(records.clone());
+        records = augment_records(records, config, &cli.augment_args.options(config))?;
+    }
+
+    let records = records
+        .into_iter()
+        .map(|r| r.expand_leds(&active, led_count))
+        .collect();
+    Ok((records, excluded))
+}
+
+/// Fills the missing values of `records` and of the points of the augment
+/// boxes. Fails if the interpolation method cannot be used with `config`.
+pub fn augment_records(
+    mut records: Vec<RssRecord>,
+    config: &Config,
+    options: &AugmentOptions,
+) -> Result<Vec<RssRecord>, Box<dyn Error>> {
+    augment::populate_points(
+        &mut records,
+        &config.augment_boxes,
+        &config.augment_exclude,
+        config,
+    );
+    let point_map = point_map::RssMap::from_records(&records, config.grid_resolution);
+    let method = options
+        .strategy
+        .map_or_else(|| config.interpolation.clone(), Into::into);
+    let augmenter = interpolate::build_augmenter(&method, &point_map, config)?;
+    let augment_pb = ProgressBar::new(0).with_style(config::pb_style());
+    augment_pb.set_message("Augmenting data");
+    let records = augment::augment_iteratively(
+        point_map,
+        &records,
+        augmenter.as_ref(),
+        config,
+        options,
+        &augment_pb,
+    );
+    augment_pb.finish();
+    Ok(records)
+}
+
+/// Runs the pipeline on `records`, processing rooms separately so that
+/// neighbors never cross rooms. LEDs excluded in a room are missing in its
+/// records. Returns the records and the LEDs excluded in every room, which
+/// can be left out of the output.
+pub fn build_radio_map(
+    records: Vec<RssRecord>,
+    config: &Config,
+    pipeline: &PipelineArgs,
+) -> Result<(Vec<RssRecord>, Vec<usize>), Box<dyn Error>> {
+    let mut records_out = Vec::new();
+    let mut excluded: Option<Vec<usize>> = None;
+    for (room, room_records) in rss_record::by_room(records) {
+        if let Some(room) = &room {
+            eprintln!("Processing room {}", room);
         }
+        let room_config = config.for_room(room.as_deref())?;
+        let (processed, room_excluded) =
+            process_room(room_records, room.as_deref(), room_config, pipeline)?;
+        records_out.extend(processed.into_iter().map(|r| r.with_room(room.clone())));
+        excluded = Some(match excluded {
+            Some(excluded) => excluded
+                .into_iter()
+                .filter(|i| room_excluded.contains(i))
+                .collect(),
+            None => room_excluded,
+        });
     }
+    Ok((records_out, excluded.unwrap_or_default()))
+}
 
-    let output = cli.output.as_ref().map_or_else(
-        || -> io::Result<Box<dyn std::io::Write>> { Ok(Box::new(std::io::stdout())) },
-        |path| -> io::Result<Box<dyn std::io::Write>> {
-            Ok(Box::new(std::fs::File::create(path)?))
-        },
-    )?;
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
 
-    let mut wtr = csv::Writer::from_writer(output);
-    let headers = ["x", "y"]
-        .into_iter()
-        .map(|s| s.to_owned())
-        .chain((0..config.led_count).map(|i| format!("led_{}", i)));
-    wtr.write_record(headers)?;
-
-    for record in records {
-        let mut row = vec![record.point.x.to_string(), record.point.y.to_string()];
-        row.extend(record.rss.iter().map(|rss| rss.to_string()));
-        wtr.write_record(row)?;
+    let config = if let Some(config_path) = &cli.config {
+        Config::from_file(config_path)?
+    } else {
+        Config::default()
+    };
+
+    if let Some(command) = cli.command {
+        return match command {
+            Command::Calibrate {
+                input,
+                output,
+                max_iters,
+            } => calibrate::run(
+                &input,
+                output.as_deref(),
+                max_iters,
+                cli.config.as_deref(),
+                &config,
+            ),
+            Command::LocateLeds {
+                input,
+                output,
+                method,
+                max_iters,
+            } => locate_leds::run(
+                &input,
+                output.as_deref(),
+                method,
+                max_iters,
+                cli.config.as_deref(),
+                &config,
+            ),
+            Command::Locate {
+                radio_map,
+                queries,
+                output,
+                k,
+                metric,
+                weighted,
+            } => locate::run(
+                &radio_map,
+                &queries,
+                output.as_deref(),
+                &Knn {
+                    k,
+                    metric,
+                    weighted,
+                },
+                &config,
+            ),
+            Command::Simulate {
+                output,
+                truth,
+                survey,
+                paths,
+                step,
+                samples,
+                noise_std,
+                shot_noise,
+                outlier_fraction,
+                dead_regions,
+                dead_radius,
+                seed,
+            } => simulate::run(
+                output.as_deref(),
+                truth.as_deref(),
+                &Simulation {
+                    survey,
+                    paths,
+                    step: step
+                        .unwrap_or(config.grid_resolution as f32)
+                        .max(f32::EPSILON),
+                    samples,
+                    noise_std,
+                    shot_noise,
+                    outlier_fraction,
+                    dead_regions,
+                    dead_radius: dead_radius.unwrap_or(config.augm_dist as f32),
+                    seed,
+                },
+                &config,
+            ),
+            Command::BenchAugment {
+                input,
+                output,
+                split,
+                test_fraction,
+                folds,
+                block_size,
+                seed,
+                bin_width,
+                augment_args,
+            } => bench_augment::run(
+                &input,
+                output.as_deref(),
+                &Split {
+                    method: split,
+                    test_fraction,
+                    folds,
+                    block_size: block_size.unwrap_or(2 * config.augm_dist).max(1),
+                    seed,
+                },
+                bin_width.unwrap_or(config.grid_resolution).max(1),
+                &augment_args.options(&config),
+                &config,
+            ),
+            Command::Evaluate {
+                input,
+                output,
+                split,
+                test_fraction,
+                folds,
+                block_size,
+                seed,
+                k,
+                metric,
+                weighted,
+                pipeline,
+            } => evaluate::run(
+                &input,
+                output.as_deref(),
+                &Split {
+                    method: split,
+                    test_fraction,
+                    folds,
+                    block_size: block_size.unwrap_or(2 * config.augm_dist).max(1),
+                    seed,
+                },
+                &Knn {
+                    k,
+                    metric,
+                    weighted,
+                },
+                &config,
+                &pipeline,
+            ),
+            Command::Trilaterate {
+                queries,
+                output,
+                leds,
+                solve_height,
+                max_iters,
+            } => trilaterate::run(
+                &queries,
+                output.as_deref(),
+                leds,
+                solve_height,
+                max_iters,
+                &config,
+            ),
+        };
     }
 
+    let input = cli
+        .input
+        .as_deref()
+        .expect("input is required without a subcommand");
+    let records = rss_record::read_candidates(input, &config)?;
+    let (records, excluded) = build_radio_map(records, &config, &cli.pipeline)?;
+
+    let output = open_output(cli.output.as_deref())?;
+    let extra = ExtraColumns {
+        uncertainty: cli.uncertainty,
+        provenance: cli.provenance,
+    };
+    let leds = (0..config.led_count)
+        .filter(|i| !excluded.contains(i))
+        .collect::<Vec<_>>();
+    rss_record::write_records(output, &records, &leds, extra)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{augment::predict_rss, led_health::HealthCheck, point::Point};
+
+    #[test]
+    fn config_is_accepted_before_and_after_subcommands() {
+        for args in [
+            &["-c", "conf.toml", "locate", "map.csv", "q.csv"][..],
+            &["locate", "-c", "conf.toml", "map.csv", "q.csv"],
+            &["-c", "conf.toml", "calibrate", "in.csv"],
+            &["--config", "conf.toml", "simulate"],
+        ] {
+            let cli =
+                Cli::try_parse_from(std::iter::once("process_data").chain(args.iter().copied()))
+                    .unwrap_or_else(|e| panic!("{:?}: {}", args, e));
+            assert_eq!(cli.config.as_deref(), Some(Path::new("conf.toml")));
+            assert!(cli.command.is_some());
+        }
+        let cli = Cli::try_parse_from(["process_data", "-c", "conf.toml", "in.csv"]).unwrap();
+        assert_eq!(cli.input.as_deref(), Some(Path::new("in.csv")));
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn uncertainty_needs_cleaning_or_augmentation() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["process_data", "in.csv"]
+                    .into_iter()
+                    .chain(args.iter().copied()),
+            )
+        };
+        assert!(parse(&["--uncertainty"]).is_err());
+        assert!(parse(&["--clean", "--uncertainty"]).is_ok());
+        assert!(parse(&["--augment", "--uncertainty"]).is_ok());
+    }
+
+    #[test]
+    fn leds_are_excluded_per_room() {
+        let room_config = Config {
+            clean_dist: 60,
+            grid_resolution: 50,
+            led_health: HealthCheck {
+                dead_level: 0.0,
+                ..HealthCheck::default()
+            },
+            ..Config::default()
+        };
+        let config = Config {
+            rooms: [("a", &room_config), ("b", &room_config)]
+                .into_iter()
+                .map(|(room, config)| (room.to_owned(), config.clone()))
+                .collect(),
+            ..room_config.clone()
+        };
+        let mut records = Vec::new();
+        for room in ["a", "b"] {
+            for x in 0..60 {
+                for y in 0..60 {
+                    let p = Point::new(50 * x, 50 * y);
+                    let mut rss = (0..config.led_count)
+                        .map(|i| predict_rss(&p, i, &config))
+                        .collect::<Vec<_>>();
+                    // LED 5 is dead in room a only
+                    if room == "a" {
+                        rss[5] = 0.0;
+                    }
+                    records.push(RssRecord::new(p, rss).with_room(Some(room.to_owned())));
+                }
+            }
+        }
+        let pipeline = PipelineArgs {
+            check_leds: Some(HealthAction::Exclude),
+            clean: false,
+            clean_augment_iters: 1,
+            clean_report: None,
+            augment: false,
+            augment_args: AugmentArgs::default(),
+        };
+        let (records, excluded) = build_radio_map(records, &config, &pipeline).unwrap();
+        assert!(excluded.is_empty());
+        let led_5 = |room: &str| {
+            records
+                .iter()
+                .filter(|r| r.room.as_deref() == Some(room))
+                .map(|r| r.rss[5])
+                .collect::<Vec<_>>()
+        };
+        assert!(led_5("a").iter().all(|v| v.is_nan()));
+        assert!(led_5("b").iter().all(|v| v.is_finite()));
+    }
+}
@@ -4,37 +4,123 @@ use serde::Deserialize;
 
 #[derive(Default, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Point {
-    pub x: usize,
-    pub y: usize,
+    pub x: i64,
+    pub y: i64,
 }
 
 impl Point {
     pub fn dist_sq(&self, other: &Point) -> usize {
-        let dx = self.x as i64 - other.x as i64;
-        let dy = self.y as i64 - other.y as i64;
+        let dx = self.x - other.x;
+        let dy = self.y - other.y;
         (dx * dx + dy * dy) as usize
     }
 
-    pub fn new(x: usize, y: usize) -> Self {
+    pub fn new(x: i64, y: i64) -> Self {
         Point { x, y }
     }
 
     pub fn with_resolution(&self, resolution: usize) -> Self {
+        let r = resolution as i64;
         Point {
-            x: self.x / resolution * resolution,
-            y: self.y / resolution * resolution,
+            x: self.x.div_euclid(r) * r,
+            y: self.y.div_euclid(r) * r,
         }
     }
 }
 
-impl From<&Point> for [usize; 2] {
-    fn from(p: &Point) -> [usize; 2] {
+/// Position with sub-grid precision, in the same units as `Point`.
+#[derive(Default, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Position {
+    pub fn new(x: f32, y: f32) -> Self {
+        Position { x, y }
+    }
+
+    pub fn dist(&self, other: &Position) -> f32 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+
+    /// Grid points of spacing `resolution` standing in for this position,
+    /// with their weights.
+    pub fn snap(&self, resolution: usize, policy: SnapPolicy) -> Vec<(Point, f32)> {
+        let r = resolution as f32;
+        let (gx, gy) = (self.x / r, self.y / r);
+        let step = resolution as i64;
+        let grid = |gx: f32, gy: f32| Point::new(gx as i64 * step, gy as i64 * step);
+        match policy {
+            SnapPolicy::Nearest => vec![(grid(gx.round(), gy.round()), 1.0)],
+            SnapPolicy::Floor => vec![(grid(gx.floor(), gy.floor()), 1.0)],
+            SnapPolicy::Bilinear => {
+                let (x0, y0) = (gx.floor(), gy.floor());
+                let (fx, fy) = (gx - x0, gy - y0);
+                [
+                    (grid(x0, y0), (1.0 - fx) * (1.0 - fy)),
+                    (grid(x0 + 1.0, y0), fx * (1.0 - fy)),
+                    (grid(x0, y0 + 1.0), (1.0 - fx) * fy),
+                    (grid(x0 + 1.0, y0 + 1.0), fx * fy),
+                ]
+                .into_iter()
+                .filter(|(_, w)| *w > 0.0)
+                .collect()
+            }
+        }
+    }
+}
+
+/// Length unit of coordinates.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Mm,
+    Cm,
+    M,
+}
+
+impl Unit {
+    /// Factor converting lengths in this unit to `other`.
+    pub fn factor_to(self, other: Unit) -> f32 {
+        let mm = |unit| match unit {
+            Unit::Mm => 1.0,
+            Unit::Cm => 10.0,
+            Unit::M => 1000.0,
+        };
+        mm(self) / mm(other)
+    }
+}
+
+/// How measurement positions are mapped onto the grid.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapPolicy {
+    /// Closest grid point
+    Nearest,
+    /// Grid point below and to the left
+    Floor,
+    /// The four surrounding grid points, weighted by proximity
+    Bilinear,
+}
+
+impl From<&Point> for Position {
+    fn from(p: &Point) -> Self {
+        Position {
+            x: p.x as f32,
+            y: p.y as f32,
+        }
+    }
+}
+
+impl From<&Point> for [i64; 2] {
+    fn from(p: &Point) -> [i64; 2] {
         [p.x, p.y]
     }
 }
 
-impl From<&[usize; 2]> for Point {
-    fn from(p: &[usize; 2]) -> Self {
+impl From<&[i64; 2]> for Point {
+    fn from(p: &[i64; 2]) -> Self {
         Point { x: p[0], y: p[1] }
     }
 }
@@ -44,8 +130,8 @@ impl Add<usize> for &Point {
 
     fn add(self, rhs: usize) -> Self::Output {
         Point {
-            x: self.x + rhs,
-            y: self.y + rhs,
+            x: self.x + rhs as i64,
+            y: self.y + rhs as i64,
         }
     }
 }
@@ -55,8 +141,8 @@ impl Sub<usize> for &Point {
 
     fn sub(self, rhs: usize) -> Self::Output {
         Point {
-            x: self.x.saturating_sub(rhs),
-            y: self.y.saturating_sub(rhs),
+            x: self.x - rhs as i64,
+            y: self.y - rhs as i64,
         }
     }
 }
@@ -1,81 +1,173 @@
-use crate::{point::Point, rss_record::RssRecord};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    point::Point,
+    rss_record::{RssArr, RssRecord},
+};
+
+/// Maps with a lower fraction of occupied cells use sparse storage.
+const SPARSE_FILL_RATIO: f32 = 0.1;
 
 #[derive(Debug, Clone)]
 pub struct PointMapConfig {
+    /// Grid point with the smallest coordinates
+    pub origin: Point,
     pub x_size: usize,
     pub y_size: usize,
     pub resolution: usize,
 }
 
+impl PointMapConfig {
+    /// Smallest map with the given resolution covering all `points`.
+    pub fn bounding<'a>(points: impl IntoIterator<Item = &'a Point>, resolution: usize) -> Self {
+        let (min, max) = points.into_iter().fold(
+            (
+                Point::new(i64::MAX, i64::MAX),
+                Point::new(i64::MIN, i64::MIN),
+            ),
+            |(min, max), p| {
+                (
+                    Point::new(min.x.min(p.x), min.y.min(p.y)),
+                    Point::new(max.x.max(p.x), max.y.max(p.y)),
+                )
+            },
+        );
+        if min.x > max.x {
+            return PointMapConfig {
+                origin: Point::default(),
+                x_size: 0,
+                y_size: 0,
+                resolution,
+            };
+        }
+        let origin = min.with_resolution(resolution);
+        PointMapConfig {
+            origin,
+            x_size: (max.x - origin.x) as usize + resolution,
+            y_size: (max.y - origin.y) as usize + resolution,
+            resolution,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Storage<T> {
+    Dense(Vec<T>),
+    /// Only the cells written to, with the value of all other cells
+    Sparse {
+        cells: HashMap<usize, T>,
+        empty: T,
+    },
+}
+
+impl<T> Storage<T> {
+    fn get(&self, idx: usize) -> &T {
+        match self {
+            Storage::Dense(data) => &data[idx],
+            Storage::Sparse { cells, empty } => cells.get(&idx).unwrap_or(empty),
+        }
+    }
+
+    /// Value of the cell, `None` for a cell never written to in sparse storage.
+    fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        match self {
+            Storage::Dense(data) => Some(&mut data[idx]),
+            Storage::Sparse { cells, .. } => cells.get_mut(&idx),
+        }
+    }
+
+    fn insert(&mut self, idx: usize, value: T) {
+        match self {
+            Storage::Dense(data) => data[idx] = value,
+            Storage::Sparse { cells, .. } => {
+                cells.insert(idx, value);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PointMap<T> {
-    data: Vec<T>,
+    data: Storage<T>,
     conf: PointMapConfig,
 }
 
-fn extract_points(raw_records: &[RssRecord]) -> Vec<Point> {
-    let mut points = raw_records.iter().map(|r| r.point).collect::<Vec<_>>();
-    points.sort_unstable();
-    points.dedup();
-    points
-}
+/// Candidate RSS vectors measured at each point.
+pub type RssMap = PointMap<Vec<RssArr>>;
 
-impl PointMap<Vec<f32>> {
+impl<T> PointMap<Vec<T>> {
     pub fn from_raw_records(
-        records: impl IntoIterator<Item = (Point, f32)>,
+        records: impl IntoIterator<Item = (Point, T)>,
         config: PointMapConfig,
     ) -> Self {
-        let mut map = Self::new(config);
+        let records = records.into_iter().collect::<Vec<_>>();
+        let occupied = records.iter().map(|(p, _)| *p).collect::<HashSet<_>>();
+        let mut map = Self::with_occupancy(config, occupied.len());
         for (p, rss) in records {
             map.add_record(p, rss);
         }
         map
     }
 
-    pub fn add_record(&mut self, p: Point, rss: f32) {
-        self[p].push(rss);
+    /// Creates a map just large enough to hold `records`.
+    pub fn with_inferred_bounds(records: Vec<(Point, T)>, resolution: usize) -> Self {
+        let config = PointMapConfig::bounding(records.iter().map(|(p, _)| p), resolution);
+        Self::from_raw_records(records, config)
     }
-}
 
-impl PointMap<Option<f32>> {
-    pub fn missing_points(&self) -> Vec<Point> {
-        let mut res = Vec::new();
-        for (idx, rss) in self.data.iter().enumerate() {
-            if rss.is_none() {
-                res.push(Point::new(
-                    idx % self.conf.x_size * self.conf.resolution,
-                    idx / self.conf.x_size * self.conf.resolution,
-                ));
-            }
+    /// Adds `value` to the values at `p`. Panics if `p` is not a grid point of the map.
+    pub fn add_record(&mut self, p: Point, value: T) {
+        match self.get_mut(p) {
+            Some(values) => values.push(value),
+            None => self.insert(p, vec![value]),
         }
-        res
     }
 
-    pub fn to_records(&self) -> Vec<(Point, f32)> {
-        self.data
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, rss)| {
-                rss.map(|rss| {
-                    (
-                        Point::new(
-                            idx % self.conf.x_size * self.conf.resolution,
-                            idx / self.conf.x_size * self.conf.resolution,
-                        ),
-                        rss,
-                    )
-                })
-            })
+    /// Points holding at least one value, in row-major order.
+    pub fn points(&self) -> Vec<Point> {
+        let mut idxs = self
+            .occupied()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        idxs.sort_unstable();
+        idxs.into_iter().map(|idx| self.point_of(idx)).collect()
+    }
+
+    /// Values at `p`, empty if there are none or `p` is not a grid point of the map.
+    pub fn values(&self, p: &Point) -> &[T] {
+        self.get(*p).map_or(&[], Vec::as_slice)
+    }
+
+    /// Values of all points within `r` of `p`, including `p` itself.
+    pub fn in_range(&self, p: &Point, r: usize) -> Vec<(Point, &T)> {
+        self.within_radius(*p, r)
+            .into_iter()
+            .flat_map(|(q, values)| values.iter().map(move |v| (q, v)))
             .collect()
     }
+}
+
+impl RssMap {
+    /// Map of the RSS vectors of `records`, just large enough to hold them.
+    pub fn from_records(records: &[RssRecord], resolution: usize) -> Self {
+        Self::with_inferred_bounds(
+            records.iter().map(|r| (r.point, r.rss.clone())).collect(),
+            resolution,
+        )
+    }
+}
 
+impl<T: Copy> PointMap<Option<T>> {
+    /// Map with `count` of the values of this map, at the points drawn from
+    /// `point_gen`. There must be at least `count` values.
     pub fn subsample(&self, mut point_gen: impl FnMut() -> Point, mut count: usize) -> Self {
-        let mut res = Self::new(self.conf.clone());
+        let mut res = Self::with_occupancy(self.extent(), count);
         while count > 0 {
             let p = point_gen();
-            if let Some(rss) = self[p] {
+            if let Some(&Some(value)) = self.get(p) {
                 if res[p].is_none() {
-                    res[p] = Some(rss);
+                    res.insert(p, Some(value));
                     count -= 1;
                 }
             }
@@ -85,54 +177,172 @@ impl PointMap<Option<f32>> {
 }
 
 impl<T> PointMap<T> {
-    fn get_index(&self, p: Point) -> usize {
-        p.y as usize * self.conf.x_size + p.x as usize
+    /// Index of the cell of `p`. Points between grid points have none, so
+    /// that they are never snapped to a cell unnoticed.
+    fn get_index(&self, p: Point) -> Option<usize> {
+        let dx = p.x - self.conf.origin.x;
+        let dy = p.y - self.conf.origin.y;
+        let resolution = self.conf.resolution as i64;
+        if dx < 0 || dy < 0 || dx % resolution != 0 || dy % resolution != 0 {
+            return None;
+        }
+        let x = dx as usize / self.conf.resolution;
+        let y = dy as usize / self.conf.resolution;
+        (x < self.conf.x_size && y < self.conf.y_size).then_some(y * self.conf.x_size + x)
+    }
+
+    fn point_of(&self, idx: usize) -> Point {
+        let resolution = self.conf.resolution as i64;
+        Point::new(
+            self.conf.origin.x + (idx % self.conf.x_size) as i64 * resolution,
+            self.conf.origin.y + (idx / self.conf.x_size) as i64 * resolution,
+        )
+    }
+
+    /// Configuration creating a map of the same extent.
+    fn extent(&self) -> PointMapConfig {
+        PointMapConfig {
+            origin: self.conf.origin,
+            x_size: self.conf.x_size * self.conf.resolution,
+            y_size: self.conf.y_size * self.conf.resolution,
+            resolution: self.conf.resolution,
+        }
+    }
+
+    /// Cells holding a value, which are all cells of a dense map.
+    fn occupied(&self) -> Box<dyn Iterator<Item = (usize, &T)> + '_> {
+        match &self.data {
+            Storage::Dense(data) => Box::new(data.iter().enumerate()),
+            Storage::Sparse { cells, .. } => Box::new(cells.iter().map(|(idx, v)| (*idx, v))),
+        }
+    }
+
+    /// Value at `p`, or `None` if `p` is not a grid point of the map.
+    pub fn get(&self, p: Point) -> Option<&T> {
+        self.get_index(p).map(|idx| self.data.get(idx))
+    }
+
+    /// Value at `p`, or `None` if `p` is not a grid point of the map or, with sparse
+    /// storage, was never written to.
+    pub fn get_mut(&mut self, p: Point) -> Option<&mut T> {
+        self.get_index(p).and_then(|idx| self.data.get_mut(idx))
+    }
+
+    /// Sets the value at `p`. Panics if `p` is not a grid point of the map.
+    pub fn insert(&mut self, p: Point, value: T) {
+        let idx = self
+            .get_index(p)
+            .unwrap_or_else(|| panic!("point {:?} is not a grid point of the map", p));
+        self.data.insert(idx, value);
     }
 
+    /// Cell ranges within `r` of `p` along both axes, clamped to the map.
     fn ranges(&self, p: Point, r: usize) -> (usize, usize, usize, usize) {
-        let x0 = p.x.saturating_sub(r) / self.conf.resolution;
-        let x1 = ((p.x + r) / self.conf.resolution).min(self.conf.x_size);
-        let y0 = p.y.saturating_sub(r) / self.conf.resolution;
-        let y1 = ((p.y + r) / self.conf.resolution).min(self.conf.y_size);
+        let resolution = self.conf.resolution as i64;
+        let r = r as i64;
+        let clamp = |cell: i64, size: usize| cell.clamp(0, size as i64) as usize;
+        // First cell at or above the lower bound, first cell beyond the upper
+        let lower = |v: i64, origin: i64| -(origin - v).div_euclid(resolution);
+        let upper = |v: i64, origin: i64| (v - origin).div_euclid(resolution) + 1;
+        let origin = self.conf.origin;
+        let x0 = clamp(lower(p.x - r, origin.x), self.conf.x_size);
+        let x1 = clamp(upper(p.x + r, origin.x), self.conf.x_size);
+        let y0 = clamp(lower(p.y - r, origin.y), self.conf.y_size);
+        let y1 = clamp(upper(p.y + r, origin.y), self.conf.y_size);
         (x0, x1, y0, y1)
     }
 
-    pub fn within_radius(&self, p: Point, r: usize) -> Vec<&T> {
-        let mut res = Vec::new();
-        let (x0, x1, y0, y1) = self.ranges(p, r);
-        for y in y0..y1 {
-            for x in x0..x1 {
-                let p2 = Point::new(x * self.conf.resolution, y * self.conf.resolution);
-                if p.dist_sq(&p2) <= r * r {
-                    res.push(&self[p2]);
-                }
-            }
-        }
-        res
+    /// Points within `r` of `p` with their values, in row-major order. With
+    /// sparse storage only the cells holding a value are returned.
+    pub fn within_radius(&self, p: Point, r: usize) -> Vec<(Point, &T)> {
+        self.within_square(p, r)
+            .into_iter()
+            .filter(|(q, _)| p.dist_sq(q) <= r * r)
+            .collect()
     }
 
-    pub fn within_square(&self, p: Point, r: usize) -> Vec<&T> {
-        let mut res = Vec::new();
+    /// Points at most `r` away from `p` along both axes with their values, in
+    /// row-major order. With sparse storage only the cells holding a value are
+    /// returned.
+    pub fn within_square(&self, p: Point, r: usize) -> Vec<(Point, &T)> {
         let (x0, x1, y0, y1) = self.ranges(p, r);
-        for y in y0..y1 {
-            for x in x0..x1 {
-                res.push(&self.data[y * self.conf.x_size + x]);
+        match &self.data {
+            Storage::Dense(data) => self
+                .square_indices(x0, x1, y0, y1)
+                .map(|idx| (self.point_of(idx), &data[idx]))
+                .collect(),
+            Storage::Sparse { cells, .. } if cells.len() >= (x1 - x0) * (y1 - y0) => self
+                .square_indices(x0, x1, y0, y1)
+                .filter_map(|idx| cells.get(&idx).map(|v| (self.point_of(idx), v)))
+                .collect(),
+            // Fewer values than cells in the square, look at the values only
+            Storage::Sparse { cells, .. } => {
+                let mut idxs = cells
+                    .keys()
+                    .copied()
+                    .filter(|&idx| {
+                        let (x, y) = (idx % self.conf.x_size, idx / self.conf.x_size);
+                        (x0..x1).contains(&x) && (y0..y1).contains(&y)
+                    })
+                    .collect::<Vec<_>>();
+                idxs.sort_unstable();
+                idxs.into_iter()
+                    .map(|idx| (self.point_of(idx), &cells[&idx]))
+                    .collect()
             }
         }
-        res
+    }
+
+    /// Indices of the cells in the given ranges, in row-major order.
+    fn square_indices(
+        &self,
+        x0: usize,
+        x1: usize,
+        y0: usize,
+        y1: usize,
+    ) -> impl Iterator<Item = usize> + '_ {
+        (y0..y1).flat_map(move |y| (x0..x1).map(move |x| y * self.conf.x_size + x))
     }
 }
 
 impl<T: Default> PointMap<T> {
+    /// Creates a map with dense storage.
     pub fn new(config: PointMapConfig) -> Self {
-        let data = std::iter::repeat_with(T::default)
-            .take(config.x_size * config.y_size)
-            .collect();
-        let x = config.x_size / config.resolution;
-        let y = config.y_size / config.resolution;
+        Self::with_storage(config, false)
+    }
+
+    /// Creates a map with sparse storage.
+    pub fn new_sparse(config: PointMapConfig) -> Self {
+        Self::with_storage(config, true)
+    }
+
+    /// Creates a map expected to hold `occupied` cells, choosing sparse
+    /// storage if they are a small fraction of all cells.
+    pub fn with_occupancy(config: PointMapConfig, occupied: usize) -> Self {
+        let cells =
+            config.x_size.div_ceil(config.resolution) * config.y_size.div_ceil(config.resolution);
+        if (occupied as f32) < SPARSE_FILL_RATIO * cells as f32 {
+            Self::new_sparse(config)
+        } else {
+            Self::new(config)
+        }
+    }
+
+    fn with_storage(config: PointMapConfig, sparse: bool) -> Self {
+        let x = config.x_size.div_ceil(config.resolution);
+        let y = config.y_size.div_ceil(config.resolution);
+        let data = if sparse {
+            Storage::Sparse {
+                cells: HashMap::new(),
+                empty: T::default(),
+            }
+        } else {
+            Storage::Dense(std::iter::repeat_with(T::default).take(x * y).collect())
+        };
         PointMap {
             data,
             conf: PointMapConfig {
+                origin: config.origin.with_resolution(config.resolution),
                 x_size: x,
                 y_size: y,
                 resolution: config.resolution,
@@ -144,13 +354,143 @@ impl<T: Default> PointMap<T> {
 impl<T> std::ops::Index<Point> for PointMap<T> {
     type Output = T;
 
+    /// Panics if `p` is not a grid point of the map, see `get` for a checked version.
     fn index(&self, p: Point) -> &Self::Output {
-        &self.data[self.get_index(p)]
+        self.get(p)
+            .unwrap_or_else(|| panic!("point {:?} is not a grid point of the map", p))
     }
 }
 
 impl<T> std::ops::IndexMut<Point> for PointMap<T> {
+    /// Panics if `p` has no value, see `insert` to add one.
     fn index_mut(&mut self, p: Point) -> &mut Self::Output {
-        &mut self.data[self.get_index(p)]
+        self.get_mut(p)
+            .unwrap_or_else(|| panic!("point {:?} has no value in the map", p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inferred_bounds_cover_all_records() {
+        let records = vec![
+            (Point::new(-20, 30), 1.0),
+            (Point::new(40, -10), 2.0),
+            (Point::new(40, -10), 3.0),
+        ];
+        let map = PointMap::with_inferred_bounds(records, 10);
+        assert_eq!(map.values(&Point::new(-20, 30)), &[1.0]);
+        assert_eq!(map.values(&Point::new(40, -10)), &[2.0, 3.0]);
+        assert_eq!(map.points(), vec![Point::new(40, -10), Point::new(-20, 30)]);
+    }
+
+    #[test]
+    fn within_radius_stays_within_radius() {
+        let records = (-5..=5)
+            .flat_map(|x| (-5..=5).map(move |y| (Point::new(x * 10, y * 10), ())))
+            .collect();
+        let map = PointMap::with_inferred_bounds(records, 10);
+        // A radius that is not a multiple of the resolution
+        for (q, _) in map.within_radius(Point::new(0, 0), 25) {
+            assert!(q.dist_sq(&Point::new(0, 0)) <= 25 * 25, "{:?}", q);
+        }
+        let (x0, x1, _, _) = map.ranges(Point::new(0, 0), 25);
+        assert_eq!(map.point_of(x0).x, -20);
+        assert_eq!(map.point_of(x1 - 1).x, 20);
+    }
+
+    fn square(resolution: usize, sparse: bool) -> PointMap<Vec<f32>> {
+        let config = PointMapConfig {
+            origin: Point::new(0, 0),
+            x_size: 1000,
+            y_size: 1000,
+            resolution,
+        };
+        if sparse {
+            PointMap::new_sparse(config)
+        } else {
+            PointMap::new(config)
+        }
+    }
+
+    #[test]
+    fn sparse_get_mut_does_not_insert() {
+        let mut map = square(10, true);
+        assert!(map.get_mut(Point::new(50, 50)).is_none());
+        assert!(map.points().is_empty());
+        map.add_record(Point::new(50, 50), 1.0);
+        map.add_record(Point::new(50, 50), 2.0);
+        map.get_mut(Point::new(50, 50)).unwrap().push(3.0);
+        assert_eq!(map.values(&Point::new(50, 50)), &[1.0, 2.0, 3.0]);
+        assert_eq!(map.points(), vec![Point::new(50, 50)]);
+    }
+
+    #[test]
+    fn sparse_and_dense_agree() {
+        let mut sparse = square(10, true);
+        let mut dense = square(10, false);
+        for (k, p) in [(0, 0), (10, 0), (30, 40), (990, 990)]
+            .into_iter()
+            .enumerate()
+        {
+            sparse.add_record(Point::new(p.0, p.1), k as f32);
+            dense.add_record(Point::new(p.0, p.1), k as f32);
+        }
+        assert_eq!(sparse.points(), dense.points());
+        for r in [0, 10, 50, 2000] {
+            let p = Point::new(20, 20);
+            assert_eq!(sparse.in_range(&p, r), dense.in_range(&p, r));
+        }
+    }
+
+    #[test]
+    fn within_square_includes_the_corners() {
+        for sparse in [true, false] {
+            let mut map = square(10, sparse);
+            for p in [(0, 0), (20, 20), (40, 40), (50, 20)] {
+                map.add_record(Point::new(p.0, p.1), 1.0);
+            }
+            let filled = |cells: Vec<(Point, &Vec<f32>)>| {
+                cells
+                    .into_iter()
+                    .filter(|(_, v)| !v.is_empty())
+                    .map(|(q, _)| q)
+                    .collect::<Vec<_>>()
+            };
+            let p = Point::new(20, 20);
+            assert_eq!(
+                filled(map.within_square(p, 20)),
+                [Point::new(0, 0), Point::new(20, 20), Point::new(40, 40)],
+                "sparse: {}",
+                sparse
+            );
+            assert_eq!(filled(map.within_radius(p, 20)), [Point::new(20, 20)]);
+            // The dense map holds every cell of the square, the sparse one
+            // only those with a value
+            let cells = if sparse { 3 } else { 25 };
+            assert_eq!(map.within_square(p, 20).len(), cells);
+        }
+    }
+
+    #[test]
+    fn points_between_grid_points_are_not_snapped() {
+        let map = RssMap::from_records(&[RssRecord::new(Point::new(10, 10), vec![1.0])], 10);
+        assert_eq!(map.values(&Point::new(10, 10)), [vec![1.0]]);
+        assert!(map.get(Point::new(15, 10)).is_none());
+        assert!(map.values(&Point::new(15, 10)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "not a grid point")]
+    fn records_between_grid_points_are_rejected() {
+        RssMap::from_records(
+            &[
+                RssRecord::new(Point::new(0, 0), vec![1.0]),
+                RssRecord::new(Point::new(15, 10), vec![1.0]),
+            ],
+            10,
+        );
     }
 }
@@ -0,0 +1,286 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Distance-dependent part of the channel gain between an LED and a receiver.
+///
+/// Only ratios of gains are used during augmentation, so absolute reference
+/// levels cancel out; they are kept so that fitted models remain meaningful.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropagationModel {
+    /// `d^-exponent`
+    InversePower { exponent: f32 },
+    /// Log-distance path loss: `PL(d) = ref_loss + 10 * exponent * log10(d / ref_dist)` in dB.
+    LogDistance {
+        exponent: f32,
+        ref_dist: f32,
+        ref_loss: f32,
+    },
+    /// Inverse power with a different exponent beyond `breakpoint`.
+    TwoSlope {
+        breakpoint: f32,
+        exponent_near: f32,
+        exponent_far: f32,
+    },
+    /// Gain linearly interpolated from `(distance, gain)` pairs with strictly
+    /// increasing distances.
+    Tabulated { curve: Vec<(f32, f32)> },
+}
+
+impl Default for PropagationModel {
+    fn default() -> Self {
+        PropagationModel::InversePower { exponent: 2.0 }
+    }
+}
+
+impl PropagationModel {
+    pub fn gain(&self, d: f32) -> f32 {
+        match self {
+            PropagationModel::InversePower { exponent } => d.powf(-exponent),
+            PropagationModel::LogDistance {
+                exponent,
+                ref_dist,
+                ref_loss,
+            } => {
+                let loss_db = ref_loss + 10.0 * exponent * (d / ref_dist).log10();
+                10.0_f32.powf(-loss_db / 10.0)
+            }
+            PropagationModel::TwoSlope {
+                breakpoint,
+                exponent_near,
+                exponent_far,
+            } => {
+                if d <= *breakpoint {
+                    d.powf(-exponent_near)
+                } else {
+                    breakpoint.powf(-exponent_near) * (d / breakpoint).powf(-exponent_far)
+                }
+            }
+            PropagationModel::Tabulated { curve } => interpolate(curve, d),
+        }
+    }
+}
+
+fn interpolate(curve: &[(f32, f32)], d: f32) -> f32 {
+    let idx = curve.partition_point(|&(x, _)| x < d);
+    if idx == 0 {
+        return curve[0].1;
+    }
+    if idx == curve.len() {
+        return curve[curve.len() - 1].1;
+    }
+    let (x0, y0) = curve[idx - 1];
+    let (x1, y1) = curve[idx];
+    y0 + (y1 - y0) * (d - x0) / (x1 - x0)
+}
+
+#[derive(Deserialize)]
+struct CurveRow {
+    distance: f32,
+    gain: f32,
+}
+
+fn load_curve(path: &Path) -> Result<Vec<(f32, f32)>, Box<dyn std::error::Error>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let curve = rdr
+        .deserialize()
+        .map(|row| row.map(|CurveRow { distance, gain }| (distance, gain)))
+        .collect::<Result<Vec<_>, csv::Error>>()?;
+    if curve.is_empty() {
+        return Err(format!("propagation curve {} is empty", path.display()).into());
+    }
+    if let Some(&(distance, gain)) = curve
+        .iter()
+        .find(|(distance, gain)| !distance.is_finite() || !gain.is_finite())
+    {
+        return Err(format!(
+            "propagation curve {} has a non-finite point ({}, {})",
+            path.display(),
+            distance,
+            gain
+        )
+        .into());
+    }
+    if let Some(w) = curve.windows(2).find(|w| w[0].0 >= w[1].0) {
+        return Err(format!(
+            "distances of propagation curve {} must be strictly increasing, {} is followed by {}",
+            path.display(),
+            w[0].0,
+            w[1].0
+        )
+        .into());
+    }
+    Ok(curve)
+}
+
+/// Checks that the parameter `name` is positive, as distances must be.
+fn check_positive(name: &str, value: f32) -> Result<f32, String> {
+    if !(value > 0.0 && value.is_finite()) {
+        return Err(format!("{} {} must be a positive distance", name, value));
+    }
+    Ok(value)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "model", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PropagationBuilder {
+    InversePower {
+        exponent: f32,
+    },
+    LogDistance {
+        exponent: f32,
+        ref_dist: f32,
+        #[serde(default)]
+        ref_loss: f32,
+    },
+    TwoSlope {
+        breakpoint: f32,
+        exponent_near: f32,
+        exponent_far: f32,
+    },
+    /// CSV file with `distance` and `gain` columns, relative to the config
+    /// file, in order of strictly increasing distance.
+    Tabulated {
+        path: PathBuf,
+    },
+}
+
+impl PropagationBuilder {
+    pub fn build(self, base_dir: &Path) -> Result<PropagationModel, Box<dyn std::error::Error>> {
+        Ok(match self {
+            PropagationBuilder::InversePower { exponent } => {
+                PropagationModel::InversePower { exponent }
+            }
+            PropagationBuilder::LogDistance {
+                exponent,
+                ref_dist,
+                ref_loss,
+            } => PropagationModel::LogDistance {
+                exponent,
+                ref_dist: check_positive("ref_dist", ref_dist)?,
+                ref_loss,
+            },
+            PropagationBuilder::TwoSlope {
+                breakpoint,
+                exponent_near,
+                exponent_far,
+            } => PropagationModel::TwoSlope {
+                breakpoint: check_positive("breakpoint", breakpoint)?,
+                exponent_near,
+                exponent_far,
+            },
+            PropagationBuilder::Tabulated { path } => PropagationModel::Tabulated {
+                curve: load_curve(&base_dir.join(path))?,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn log_distance_losses_are_in_decibels() {
+        let model = PropagationModel::LogDistance {
+            exponent: 2.0,
+            ref_dist: 1.0,
+            ref_loss: 10.0,
+        };
+        assert!((model.gain(1.0) - 0.1).abs() < 1e-6);
+        // Ten times the distance loses another 20 dB
+        assert!((model.gain(10.0) - 0.001).abs() < 1e-7);
+    }
+
+    #[test]
+    fn two_slope_gains_are_continuous_at_the_breakpoint() {
+        let model = PropagationModel::TwoSlope {
+            breakpoint: 2.0,
+            exponent_near: 1.0,
+            exponent_far: 3.0,
+        };
+        assert_eq!(model.gain(2.0), 0.5);
+        assert!((model.gain(2.0 + 1e-4) - 0.5).abs() < 1e-3);
+        assert_eq!(model.gain(4.0), 0.5 * 0.125);
+    }
+
+    #[test]
+    fn curves_are_read_relative_to_the_config_file() {
+        let dir = std::env::temp_dir().join("process_data_propagation_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("curve.csv"), "distance,gain\n1,1\n2,0.5\n").unwrap();
+        let text = "[propagation]\nmodel = \"tabulated\"\npath = \"curve.csv\"";
+        let config = Config::from_toml(text, &dir).unwrap();
+        // The curve is interpolated inside and held constant outside
+        assert_eq!(config.propagation[0].gain(1.5), 0.75);
+        assert_eq!(config.propagation[0].gain(0.5), 1.0);
+        assert_eq!(config.propagation[0].gain(3.0), 0.5);
+        assert!(Config::from_toml(text, Path::new("/nonexistent")).is_err());
+        assert!(Config::from_toml("[propagation]\nmodel = \"cubic\"", &dir).is_err());
+    }
+
+    fn build(text: &str) -> Result<(), String> {
+        Config::from_toml(&format!("[propagation]\n{}", text), Path::new("."))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn build_curve(rows: &str) -> Result<(), String> {
+        let dir = std::env::temp_dir().join("process_data_propagation_curve_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("curve.csv"), format!("distance,gain\n{}", rows)).unwrap();
+        let text = "[propagation]\nmodel = \"tabulated\"\npath = \"curve.csv\"";
+        Config::from_toml(text, &dir)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn reference_distance_must_be_positive() {
+        let text = "model = \"log_distance\"\nexponent = 2.0\nref_dist = ";
+        assert!(build(&format!("{}1.0", text)).is_ok());
+        for ref_dist in ["0.0", "-1.0"] {
+            let err = build(&format!("{}{}", text, ref_dist)).unwrap_err();
+            assert!(err.starts_with("ref_dist"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn breakpoint_must_be_positive() {
+        let text = "model = \"two_slope\"\nexponent_near = 1.0\nexponent_far = 3.0\nbreakpoint = ";
+        assert!(build(&format!("{}2.0", text)).is_ok());
+        for breakpoint in ["0.0", "-2.0"] {
+            let err = build(&format!("{}{}", text, breakpoint)).unwrap_err();
+            assert!(err.starts_with("breakpoint"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn curve_distances_must_not_decrease() {
+        let err = build_curve("2,0.5\n1,1\n").unwrap_err();
+        assert!(
+            err.ends_with("strictly increasing, 2 is followed by 1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn curve_distances_must_not_repeat() {
+        let err = build_curve("1,1\n2,0.5\n2,0.4\n").unwrap_err();
+        assert!(
+            err.ends_with("strictly increasing, 2 is followed by 2"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn curve_points_must_be_finite() {
+        for rows in ["1,1\nNaN,0.5\n", "1,1\n2,inf\n"] {
+            let err = build_curve(rows).unwrap_err();
+            assert!(err.contains("non-finite"), "{}", err);
+        }
+    }
+}
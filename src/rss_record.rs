@@ -1,65 +1,520 @@
 use serde::{de, Deserialize, Deserializer};
-use std::fmt;
+use std::{collections::BTreeMap, error::Error, fmt, io, path::Path};
 
-use crate::point::Point;
+use crate::{
+    config::Config,
+    point::{Point, Position},
+};
 
 pub type RssArr = Vec<f32>;
 
+/// Spread of the estimate behind an augmented RSS value and the number of
+/// values it was derived from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Uncertainty {
+    pub std: f32,
+    pub n: usize,
+}
+
+/// Origin of an RSS value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provenance {
+    /// No value
+    Missing,
+    /// Measured value
+    Raw,
+    /// Measured value chosen among several measurements of the same point
+    Selected,
+    /// Value filled during stage 2 of cleaning
+    Filled,
+    /// Value augmented from neighbors in the given iteration
+    Augmented(u32),
+    /// Value predicted by the channel model
+    Predicted,
+    /// Value set outside of the LED's field of view
+    OutOfFov,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Provenance::Missing => write!(f, "missing"),
+            Provenance::Raw => write!(f, "raw"),
+            Provenance::Selected => write!(f, "selected"),
+            Provenance::Filled => write!(f, "filled"),
+            Provenance::Augmented(it) => write!(f, "augmented_{}", it),
+            Provenance::Predicted => write!(f, "predicted"),
+            Provenance::OutOfFov => write!(f, "out_of_fov"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RssRecord {
     pub point: Point,
     pub rss: RssArr,
+    /// Uncertainty of each augmented value, `None` for measured values
+    pub uncertainty: Vec<Option<Uncertainty>>,
+    pub provenance: Vec<Provenance>,
+    /// Room or floor the record belongs to
+    pub room: Option<String>,
+    /// Share of a measurement split between grid points, 1 for a whole one
+    pub weight: f32,
+}
+
+impl RssRecord {
+    /// Creates a record of measured values.
+    pub fn new(point: Point, rss: RssArr) -> Self {
+        let uncertainty = vec![None; rss.len()];
+        let provenance = rss
+            .iter()
+            .map(|v| {
+                if v.is_nan() {
+                    Provenance::Missing
+                } else {
+                    Provenance::Raw
+                }
+            })
+            .collect();
+        RssRecord {
+            point,
+            rss,
+            uncertainty,
+            provenance,
+            room: None,
+            weight: 1.0,
+        }
+    }
+
+    pub fn with_room(self, room: Option<String>) -> Self {
+        RssRecord { room, ..self }
+    }
+
+    pub fn with_weight(self, weight: f32) -> Self {
+        RssRecord { weight, ..self }
+    }
+
+    /// Record of only the values of `leds`, in that order.
+    pub fn select_leds(&self, leds: &[usize]) -> Self {
+        RssRecord {
+            point: self.point,
+            rss: leds.iter().map(|&i| self.rss[i]).collect(),
+            uncertainty: leds.iter().map(|&i| self.uncertainty[i]).collect(),
+            provenance: leds.iter().map(|&i| self.provenance[i]).collect(),
+            room: self.room.clone(),
+            weight: self.weight,
+        }
+    }
+
+    /// Inverse of `select_leds`, with all other of the `led_count` values
+    /// missing.
+    pub fn expand_leds(self, leds: &[usize], led_count: usize) -> Self {
+        let mut record = RssRecord::new(self.point, vec![f32::NAN; led_count])
+            .with_room(self.room)
+            .with_weight(self.weight);
+        for (k, &i) in leds.iter().enumerate() {
+            record.rss[i] = self.rss[k];
+            record.uncertainty[i] = self.uncertainty[k];
+            record.provenance[i] = self.provenance[k];
+        }
+        record
+    }
+}
+
+/// Optional columns written after the RSS values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtraColumns {
+    /// `led_i_std` and `led_i_n`
+    pub uncertainty: bool,
+    /// `led_i_src`
+    pub provenance: bool,
+}
+
+/// RSS values measured at an arbitrary position, in the input unit.
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub position: Position,
+    pub rss: RssArr,
+    pub room: Option<String>,
+}
+
+/// How many measurements did not lie on a grid point.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SnapReport {
+    pub total: usize,
+    pub snapped: usize,
+    /// Largest distance between a measurement and its grid points, in the
+    /// configured `unit`
+    pub max_offset: f32,
+}
+
+impl fmt::Display for SnapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} measurements snapped to the grid (max offset {})",
+            self.snapped, self.total, self.max_offset
+        )
+    }
+}
+
+pub fn read_measurements(path: &Path) -> Result<Vec<Measurement>, csv::Error> {
+    csv::Reader::from_path(path)?.deserialize().collect()
+}
+
+/// Converts `measurements` to the configured unit and maps them onto the
+/// grid, with the configuration of their room. With bilinear snapping, a
+/// measurement becomes one record per surrounding grid point, weighted by
+/// its share; see `merge_shares`. Measurements must have a value for each
+/// LED of their room.
+pub fn snap_measurements(
+    measurements: Vec<Measurement>,
+    config: &Config,
+) -> Result<(Vec<RssRecord>, SnapReport), String> {
+    let mut report = SnapReport {
+        total: measurements.len(),
+        ..Default::default()
+    };
+    let mut records = Vec::with_capacity(measurements.len());
+    for (
+        row,
+        Measurement {
+            position,
+            rss,
+            room,
+        },
+    ) in measurements.into_iter().enumerate()
+    {
+        let config = config.for_room(room.as_deref())?;
+        if rss.len() != config.led_count {
+            return Err(format!(
+                "row {} has {} LED values, but {} LEDs are configured",
+                row + 1,
+                rss.len(),
+                config.led_count
+            ));
+        }
+        let scale = config.input_unit.factor_to(config.unit);
+        let position = Position::new(position.x * scale, position.y * scale);
+        let targets = position.snap(config.grid_resolution, config.snap);
+        let offset = targets
+            .iter()
+            .map(|(p, _)| position.dist(&Position::from(p)))
+            .fold(0.0, f32::max);
+        if offset > 0.0 {
+            report.snapped += 1;
+            report.max_offset = report.max_offset.max(offset);
+        }
+        for (p, w) in targets {
+            records.push(
+                RssRecord::new(p, rss.clone())
+                    .with_room(room.clone())
+                    .with_weight(w),
+            );
+        }
+    }
+    Ok((records, report))
 }
 
-impl<'de> Deserialize<'de> for RssRecord {
+/// Replaces the records of every point holding a share of a split
+/// measurement by their mean, weighted by share, over the finite values of
+/// each LED. Records of other points are kept as they are.
+pub fn merge_shares(records: Vec<RssRecord>) -> Vec<RssRecord> {
+    let mut points = BTreeMap::<(Option<String>, Point), Vec<RssRecord>>::new();
+    for record in records {
+        points
+            .entry((record.room.clone(), record.point))
+            .or_default()
+            .push(record);
+    }
+    let mut merged = Vec::with_capacity(points.len());
+    for ((room, p), records) in points {
+        if records.iter().all(|r| r.weight == 1.0) {
+            merged.extend(records);
+            continue;
+        }
+        let rss = (0..records[0].rss.len())
+            .map(|i| {
+                let (sum, weight) = records
+                    .iter()
+                    .filter(|r| r.rss[i].is_finite())
+                    .fold((0.0, 0.0), |(s, w), r| {
+                        (s + r.weight * r.rss[i], w + r.weight)
+                    });
+                if weight > 0.0 {
+                    sum / weight
+                } else {
+                    f32::NAN
+                }
+            })
+            .collect();
+        merged.push(RssRecord::new(p, rss).with_room(room));
+    }
+    merged
+}
+
+/// Reads the measurements at `path` and snaps them to the grid, reporting
+/// how many had to be moved. The shares of split measurements are kept as
+/// separate records, so that cleaning can judge them one by one.
+pub fn read_candidates(path: &Path, config: &Config) -> Result<Vec<RssRecord>, Box<dyn Error>> {
+    let (records, report) = snap_measurements(read_measurements(path)?, config)?;
+    if report.snapped > 0 {
+        eprintln!("{}", report);
+    }
+    Ok(records)
+}
+
+/// Like `read_candidates`, with the shares of split measurements merged.
+pub fn read_records(path: &Path, config: &Config) -> Result<Vec<RssRecord>, Box<dyn Error>> {
+    Ok(merge_shares(read_candidates(path, config)?))
+}
+
+/// Records grouped by room.
+pub fn by_room(records: Vec<RssRecord>) -> BTreeMap<Option<String>, Vec<RssRecord>> {
+    let mut rooms = BTreeMap::<_, Vec<_>>::new();
+    for record in records {
+        rooms.entry(record.room.clone()).or_default().push(record);
+    }
+    rooms
+}
+
+/// Reads a radio map written by the pipeline. Its positions are grid points
+/// in the configured unit already and are taken as they are.
+pub fn read_radio_map(path: &Path) -> Result<Vec<RssRecord>, Box<dyn Error>> {
+    read_measurements(path)?
+        .into_iter()
+        .map(|m| {
+            let Position { x, y } = m.position;
+            if x.fract() != 0.0 || y.fract() != 0.0 {
+                return Err(
+                    format!("radio map position ({}, {}) is not a grid point", x, y).into(),
+                );
+            }
+            Ok(RssRecord::new(Point::new(x as i64, y as i64), m.rss).with_room(m.room))
+        })
+        .collect()
+}
+
+/// Writes the values of `leds` in `records` as CSV, followed by the
+/// requested extra columns. A `room` column follows the coordinates if any
+/// record belongs to a room.
+pub fn write_records(
+    output: impl io::Write,
+    records: &[RssRecord],
+    leds: &[usize],
+    extra: ExtraColumns,
+) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(output);
+    let rooms = records.iter().any(|r| r.room.is_some());
+    let mut headers = vec!["x".to_owned(), "y".to_owned()];
+    if rooms {
+        headers.push("room".to_owned());
+    }
+    headers.extend(leds.iter().map(|i| format!("led_{}", i)));
+    if extra.uncertainty {
+        headers.extend(leds.iter().map(|i| format!("led_{}_std", i)));
+        headers.extend(leds.iter().map(|i| format!("led_{}_n", i)));
+    }
+    if extra.provenance {
+        headers.extend(leds.iter().map(|i| format!("led_{}_src", i)));
+    }
+    wtr.write_record(headers)?;
+
+    for record in records {
+        let mut row = vec![record.point.x.to_string(), record.point.y.to_string()];
+        if rooms {
+            row.push(record.room.clone().unwrap_or_default());
+        }
+        row.extend(leds.iter().map(|&i| record.rss[i].to_string()));
+        if extra.uncertainty {
+            row.extend(leds.iter().map(|&i| {
+                // Pure model predictions have no spread to report
+                record.uncertainty[i]
+                    .map(|u| u.std)
+                    .filter(|std| std.is_finite())
+                    .map_or_else(String::new, |std| std.to_string())
+            }));
+            row.extend(
+                leds.iter()
+                    .map(|&i| record.uncertainty[i].map_or_else(String::new, |u| u.n.to_string())),
+            );
+        }
+        if extra.provenance {
+            row.extend(leds.iter().map(|&i| record.provenance[i].to_string()));
+        }
+        wtr.write_record(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+impl<'de> Deserialize<'de> for Measurement {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_map(RssRecordVisitor)
+        deserializer.deserialize_map(MeasurementVisitor)
     }
 }
 
-struct RssRecordVisitor;
+struct MeasurementVisitor;
 
-impl<'de> de::Visitor<'de> for RssRecordVisitor {
-    type Value = RssRecord;
+impl<'de> de::Visitor<'de> for MeasurementVisitor {
+    type Value = Measurement;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("struct RssRecord")
+        formatter.write_str("struct Measurement")
     }
 
-    fn visit_map<V>(self, mut map: V) -> Result<RssRecord, V::Error>
+    fn visit_map<V>(self, mut map: V) -> Result<Measurement, V::Error>
     where
         V: de::MapAccess<'de>,
     {
         let mut x = None;
         let mut y = None;
+        let mut room = None;
         let mut leds = Vec::new();
 
         while let Some(key) = map.next_key::<String>()? {
             match key.as_str() {
                 "x" => {
-                    x = Some(map.next_value::<f32>()? as u32);
+                    x = Some(map.next_value::<f32>()?);
                 }
                 "y" => {
-                    y = Some(map.next_value::<f32>()? as u32);
+                    y = Some(map.next_value::<f32>()?);
                 }
-                k if k.starts_with("led_") => {
-                    leds.push(map.next_value::<f32>()?);
-                }
-                _ => {
-                    let _: de::IgnoredAny = map.next_value()?;
+                "room" => {
+                    room = Some(map.next_value::<String>()?).filter(|r| !r.is_empty());
                 }
+                k => match k.strip_prefix("led_").and_then(|i| i.parse::<usize>().ok()) {
+                    // Columns of LEDs excluded from the map are missing
+                    Some(i) => {
+                        if leds.len() <= i {
+                            leds.resize(i + 1, f32::NAN);
+                        }
+                        leds[i] = map.next_value::<f32>()?;
+                    }
+                    None => {
+                        let _: de::IgnoredAny = map.next_value()?;
+                    }
+                },
             }
         }
 
         let x = x.ok_or_else(|| de::Error::missing_field("x"))?;
         let y = y.ok_or_else(|| de::Error::missing_field("y"))?;
 
-        Ok(RssRecord {
-            point: Point { x, y },
+        Ok(Measurement {
+            position: Position { x, y },
             rss: leds,
+            room,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_leds_expand_back_to_their_columns() {
+        let record = RssRecord::new(Point::new(1, 2), vec![1.0, 2.0, 3.0]);
+        let selected = record.select_leds(&[0, 2]);
+        assert_eq!(selected.rss, vec![1.0, 3.0]);
+        let expanded = selected.expand_leds(&[0, 2], 3);
+        assert_eq!(expanded.rss[0], 1.0);
+        assert!(expanded.rss[1].is_nan());
+        assert_eq!(expanded.provenance[1], Provenance::Missing);
+        assert_eq!(expanded.rss[2], 3.0);
+
+        let config = Config::default().select_leds(&[4, 7]);
+        assert_eq!(config.led_count, 2);
+        assert_eq!(config.led_positions[1], Config::default().led_positions[7]);
+    }
+
+    #[test]
+    fn undefined_deviations_are_written_as_empty_fields() {
+        let mut record = RssRecord::new(Point::new(0, 0), vec![1.0, 2.0]);
+        record.uncertainty = vec![
+            Some(Uncertainty {
+                std: f32::NAN,
+                n: 0,
+            }),
+            Some(Uncertainty { std: 0.5, n: 3 }),
+        ];
+        let mut output = Vec::new();
+        let extra = ExtraColumns {
+            uncertainty: true,
+            provenance: false,
+        };
+        write_records(&mut output, &[record], &[0, 1], extra).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "x,y,led_0,led_1,led_0_std,led_1_std,led_0_n,led_1_n\n0,0,1,2,,0.5,0,3\n"
+        );
+    }
+
+    #[test]
+    fn measurements_are_snapped_with_their_room_configuration() {
+        let mut config = Config::default().select_leds(&[0]);
+        let room_config = Config {
+            input_unit: crate::point::Unit::Cm,
+            grid_resolution: 10,
+            ..Config::default().select_leds(&[0])
+        };
+        config.rooms.insert("cm".to_owned(), room_config);
+        let measurement = |room: Option<&str>| Measurement {
+            position: Position::new(12.0, 3.0),
+            rss: vec![1.0],
+            room: room.map(str::to_owned),
+        };
+        let (records, _) =
+            snap_measurements(vec![measurement(None), measurement(Some("cm"))], &config).unwrap();
+        assert_eq!(records[0].point, Point::new(12, 3));
+        assert_eq!(records[1].point, Point::new(120, 30));
+        assert!(snap_measurements(vec![measurement(Some("other"))], &config).is_err());
+    }
+
+    #[test]
+    fn measurements_need_a_value_per_configured_led() {
+        let path = std::env::temp_dir().join("rss_record_led_count_test.csv");
+        std::fs::write(&path, "x,y,led_0,led_1\n0,0,1,2\n").unwrap();
+        let config = Config::default().select_leds(&[0, 1, 2]);
+        let result = read_records(&path, &config);
+        std::fs::remove_file(&path).unwrap();
+        let err = result.unwrap_err().to_string();
+        assert_eq!(err, "row 1 has 2 LED values, but 3 LEDs are configured");
+    }
+
+    #[test]
+    fn split_measurements_are_kept_as_weighted_shares() {
+        let config = Config {
+            input_unit: crate::point::Unit::Cm,
+            grid_resolution: 10,
+            snap: crate::point::SnapPolicy::Bilinear,
+            ..Config::default().select_leds(&[0])
+        };
+        let measurement = |x, v| Measurement {
+            position: Position::new(x, 0.0),
+            rss: vec![v],
+            room: None,
+        };
+        let (records, report) =
+            snap_measurements(vec![measurement(0.25, 1.0), measurement(1.0, 3.0)], &config)
+                .unwrap();
+        let shares = records
+            .iter()
+            .map(|r| (r.point.x, r.rss[0], r.weight))
+            .collect::<Vec<_>>();
+        assert_eq!(shares, [(0, 1.0, 0.75), (10, 1.0, 0.25), (10, 3.0, 1.0)]);
+        assert_eq!((report.snapped, report.total), (1, 2));
+        // 2.5 mm from the nearer and 7.5 mm from the farther grid point
+        assert_eq!(report.max_offset, 7.5);
+
+        let merged = merge_shares(records);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].rss, [1.0]);
+        assert_eq!(merged[1].rss, [(0.25 + 3.0) / 1.25]);
+        assert!(merged.iter().all(|r| r.weight == 1.0));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{error::Error, path::Path};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    augment::{predict_rss, predict_rss_at},
+    config::Config,
+    open_output,
+    point::{Point, Position},
+    rss_record::{write_records, ExtraColumns, RssArr, RssRecord},
+};
+
+/// Largest multiple of the peak RSS of its LED a spike reaches.
+const OUTLIER_SCALE: f32 = 3.0;
+
+/// How the simulated survey visits the area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SurveyMode {
+    /// Every point of the augment boxes
+    Grid,
+    /// Straight walks between random points of the augment boxes
+    Paths,
+}
+
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub survey: SurveyMode,
+    /// Number of walks of the path survey
+    pub paths: usize,
+    /// Distance between the samples of a walk
+    pub step: f32,
+    /// Samples taken at every surveyed position
+    pub samples: usize,
+    /// Standard deviation of the additive Gaussian noise
+    pub noise_std: f32,
+    /// Shot noise factor, the noise variance grows by this factor times the RSS
+    pub shot_noise: f32,
+    /// Fraction of the values replaced by outliers, which are independent of
+    /// the true value: dropouts to zero or spikes above the LED's peak RSS
+    pub outlier_fraction: f32,
+    /// Number of circular regions without measurements
+    pub dead_regions: usize,
+    pub dead_radius: f32,
+    pub seed: u64,
+}
+
+/// Standard normal sample, by the Box-Muller transform.
+fn gaussian(rng: &mut StdRng) -> f32 {
+    let u1 = rng.random::<f32>().max(f32::MIN_POSITIVE);
+    let u2 = rng.random::<f32>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+impl Simulation {
+    /// Positions visited by the survey.
+    fn survey_positions(&self, grid: &[Point], rng: &mut StdRng) -> Vec<Position> {
+        match self.survey {
+            SurveyMode::Grid => grid.iter().map(Position::from).collect(),
+            SurveyMode::Paths => {
+                let mut positions = Vec::new();
+                let mut waypoint = || Position::from(&grid[rng.random_range(0..grid.len())]);
+                for _ in 0..self.paths {
+                    let (a, b) = (waypoint(), waypoint());
+                    let steps = (a.dist(&b) / self.step).floor() as usize;
+                    positions.extend((0..=steps).map(|i| {
+                        let t = if steps == 0 {
+                            0.0
+                        } else {
+                            i as f32 / steps as f32
+                        };
+                        Position::new(a.x + t * (b.x - a.x), a.y + t * (b.y - a.y))
+                    }));
+                }
+                positions
+            }
+        }
+    }
+
+    /// Noisy measurement of the true RSS `rss` of a LED whose largest true
+    /// RSS is `peak`.
+    fn measure(&self, rss: f32, peak: f32, rng: &mut StdRng) -> f32 {
+        if !rss.is_finite() {
+            return rss;
+        }
+        if rng.random::<f32>() < self.outlier_fraction {
+            return if rng.random::<bool>() {
+                0.0
+            } else {
+                peak * rng.random_range(1.0..OUTLIER_SCALE)
+            };
+        }
+        let std = (self.noise_std * self.noise_std + self.shot_noise * rss.max(0.0)).sqrt();
+        (rss + std * gaussian(rng)).max(0.0)
+    }
+}
+
+/// Generates the noise-free RSS of the points of the augment boxes and a
+/// survey of them with the noise of `simulation`. The survey is written to
+/// `output` in the input format, with positions in the `input_unit`. The
+/// ground truth is written to `truth` if present, in the format and `unit`
+/// of the radio map the pipeline writes, so that the two can be compared.
+pub fn run(
+    output: Option<&Path>,
+    truth: Option<&Path>,
+    simulation: &Simulation,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut grid = config
+        .augment_boxes
+        .iter()
+        .flat_map(|b| b.points(&config.augment_exclude))
+        .collect::<Vec<_>>();
+    grid.sort_unstable_by_key(|p| (p.y, p.x));
+    grid.dedup();
+    if grid.is_empty() {
+        return Err("the augment boxes contain no points to simulate".into());
+    }
+    let leds = (0..config.led_count).collect::<Vec<_>>();
+    let records = grid
+        .iter()
+        .map(|p| {
+            let rss = leds.iter().map(|&i| predict_rss(p, i, config)).collect();
+            RssRecord::new(*p, rss)
+        })
+        .collect::<Vec<_>>();
+    let peaks = leds
+        .iter()
+        .map(|&i| {
+            records
+                .iter()
+                .map(|r| r.rss[i])
+                .filter(|v| v.is_finite())
+                .fold(0.0, f32::max)
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(path) = truth {
+        write_records(
+            open_output(Some(path))?,
+            &records,
+            &leds,
+            ExtraColumns::default(),
+        )?;
+    }
+
+    let mut rng = StdRng::seed_from_u64(simulation.seed);
+    let dead = (0..simulation.dead_regions)
+        .map(|_| Position::from(&grid[rng.random_range(0..grid.len())]))
+        .collect::<Vec<_>>();
+    let positions = simulation
+        .survey_positions(&grid, &mut rng)
+        .into_iter()
+        .filter(|p| dead.iter().all(|d| d.dist(p) > simulation.dead_radius))
+        .collect::<Vec<_>>();
+
+    let scale = config.unit.factor_to(config.input_unit);
+    let mut wtr = csv::Writer::from_writer(open_output(output)?);
+    let mut headers = vec!["x".to_owned(), "y".to_owned()];
+    headers.extend(leds.iter().map(|i| format!("led_{}", i)));
+    wtr.write_record(headers)?;
+    for position in &positions {
+        let rss = leds
+            .iter()
+            .map(|&i| predict_rss_at(position, config.receiver_height, i, config))
+            .collect::<RssArr>();
+        for _ in 0..simulation.samples.max(1) {
+            let mut row = vec![
+                (position.x * scale).to_string(),
+                (position.y * scale).to_string(),
+            ];
+            row.extend(
+                rss.iter()
+                    .zip(&peaks)
+                    .map(|(&v, &peak)| simulation.measure(v, peak, &mut rng).to_string()),
+            );
+            wtr.write_record(row)?;
+        }
+    }
+    wtr.flush()?;
+    eprintln!(
+        "Simulated {} samples at {} positions",
+        positions.len() * simulation.samples.max(1),
+        positions.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outliers_do_not_depend_on_the_true_value() {
+        let simulation = Simulation {
+            survey: SurveyMode::Grid,
+            paths: 0,
+            step: 1.0,
+            samples: 1,
+            noise_std: 0.0,
+            shot_noise: 0.0,
+            outlier_fraction: 1.0,
+            dead_regions: 0,
+            dead_radius: 0.0,
+            seed: 0,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let (mut dropouts, mut spikes) = (0, 0);
+        for _ in 0..200 {
+            // A dark true value still gets spikes, so outliers are not scaled
+            match simulation.measure(0.0, 2.0, &mut rng) {
+                0.0 => dropouts += 1,
+                v => {
+                    assert!((2.0..2.0 * OUTLIER_SCALE).contains(&v), "{}", v);
+                    spikes += 1;
+                }
+            }
+        }
+        assert!(dropouts > 50 && spikes > 50, "{} {}", dropouts, spikes);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{error::Error, path::Path};
+
+use crate::{
+    augment::predict_rss_at,
+    config::Config,
+    fit::{levenberg_marquardt, Fit},
+    locate::read_queries,
+    open_output,
+    point::Position,
+};
+
+/// Receiver position fitted to a query.
+#[derive(Debug, Clone)]
+pub struct Trilateration {
+    pub position: Position,
+    pub height: f32,
+    /// LEDs used for the fit
+    pub leds: Vec<usize>,
+    pub fit: Fit,
+    /// Mean of the fitted RSS values
+    pub mean_rss: f32,
+}
+
+impl Trilateration {
+    /// Fit residual relative to the fitted RSS values, lower is more
+    /// confident.
+    pub fn rel_rmse(&self) -> f64 {
+        self.fit.rmse() / self.mean_rss as f64
+    }
+}
+
+/// Estimates the receiver position from the `led_count` strongest values of
+/// `rss` by inverting the channel model, also fitting the receiver height if
+/// `solve_height` is set. LEDs the model cannot predict at the starting
+/// position, such as those out of view with `fov_outside = "nan"`, are
+/// skipped. At least three values are required, four to fit the height, so
+/// that the fit is overdetermined.
+pub fn trilaterate(
+    rss: &[f32],
+    led_count: usize,
+    solve_height: bool,
+    max_iters: usize,
+    config: &Config,
+) -> Option<Trilateration> {
+    let mut leds = (0..rss.len())
+        .filter(|&i| rss[i].is_finite() && rss[i] > 0.0)
+        .collect::<Vec<_>>();
+    leds.sort_by(|&a, &b| rss[b].total_cmp(&rss[a]));
+
+    // Start from the RSS-weighted centroid of the strongest LEDs
+    let (sx, sy, sw) = leds
+        .iter()
+        .take(led_count)
+        .fold((0.0, 0.0, 0.0), |(sx, sy, sw), &i| {
+            let p = config.led_positions[i];
+            (sx + rss[i] * p.x, sy + rss[i] * p.y, sw + rss[i])
+        });
+    let start = Position::new(sx / sw, sy / sw);
+    leds.retain(|&i| predict_rss_at(&start, config.receiver_height, i, config).is_finite());
+    leds.truncate(led_count);
+    if leds.len() < 3 + solve_height as usize {
+        return None;
+    }
+    let mut initial = vec![start.x as f64, start.y as f64];
+    if solve_height {
+        initial.push(config.receiver_height as f64);
+    }
+
+    let height = |params: &[f64]| params.get(2).map_or(config.receiver_height, |&h| h as f32);
+    // A non-finite prediction makes the cost non-finite, so the fit never
+    // steps to where a used LED cannot be predicted
+    let residuals = |params: &[f64]| {
+        let position = Position::new(params[0] as f32, params[1] as f32);
+        leds.iter()
+            .map(|&i| (predict_rss_at(&position, height(params), i, config) - rss[i]) as f64)
+            .collect()
+    };
+    let fit = levenberg_marquardt(residuals, initial, max_iters);
+    let mean_rss = leds.iter().map(|&i| rss[i]).sum::<f32>() / leds.len() as f32;
+    Some(Trilateration {
+        position: Position::new(fit.params[0] as f32, fit.params[1] as f32),
+        height: height(&fit.params),
+        leds,
+        fit,
+        mean_rss,
+    })
+}
+
+pub fn run(
+    queries: &Path,
+    output: Option<&Path>,
+    led_count: usize,
+    solve_height: bool,
+    max_iters: usize,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    config.require_led_powers("trilateration")?;
+    let queries = read_queries(queries, config.led_count)?;
+    let mut wtr = csv::Writer::from_writer(open_output(output)?);
+    wtr.write_record(["id", "x", "y", "z", "leds", "rmse", "rel_rmse"])?;
+    for query in &queries {
+        let row = match trilaterate(&query.rss, led_count, solve_height, max_iters, config) {
+            Some(t) => [
+                query.id.clone(),
+                t.position.x.to_string(),
+                t.position.y.to_string(),
+                t.height.to_string(),
+                t.leds.len().to_string(),
+                t.fit.rmse().to_string(),
+                t.rel_rmse().to_string(),
+            ],
+            None => [
+                query.id.clone(),
+                "NaN".to_owned(),
+                "NaN".to_owned(),
+                "NaN".to_owned(),
+                "0".to_owned(),
+                "NaN".to_owned(),
+                "NaN".to_owned(),
+            ],
+        };
+        wtr.write_record(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FovPolicy;
+
+    fn query(position: &Position, config: &Config) -> Vec<f32> {
+        (0..config.led_count)
+            .map(|i| predict_rss_at(position, config.receiver_height, i, config))
+            .collect()
+    }
+
+    #[test]
+    fn recovers_the_position_of_a_model_query() {
+        let config = Config::default();
+        let truth = Position::new(1100.0, 900.0);
+        let t = trilaterate(&query(&truth, &config), 6, false, 100, &config).unwrap();
+        assert!(t.position.dist(&truth) < 1.0, "{:?}", t.position);
+    }
+
+    #[test]
+    fn needs_an_overdetermined_system() {
+        let config = Config::default();
+        let mut rss = query(&Position::new(1100.0, 900.0), &config);
+        let mut strongest = (0..rss.len()).collect::<Vec<_>>();
+        strongest.sort_by(|&a, &b| rss[b].total_cmp(&rss[a]));
+        for &i in &strongest[3..] {
+            rss[i] = f32::NAN;
+        }
+        assert!(trilaterate(&rss, 6, false, 100, &config).is_some());
+        assert!(trilaterate(&rss, 6, true, 100, &config).is_none());
+        rss[strongest[2]] = f32::NAN;
+        assert!(trilaterate(&rss, 6, false, 100, &config).is_none());
+    }
+
+    #[test]
+    fn skips_leds_that_cannot_be_predicted() {
+        let config = Config {
+            fov_outside: FovPolicy::Nan,
+            ..Config::default()
+        };
+        let truth = Position::new(1100.0, 900.0);
+        let mut rss = query(&truth, &config);
+        // A far LED out of view with a spurious reading
+        rss[35] = 1e-9;
+        let t = trilaterate(&rss, 36, false, 100, &config).unwrap();
+        assert!(!t.leds.contains(&35));
+        assert!(t.position.dist(&truth) < 1.0, "{:?}", t.position);
+    }
+}